@@ -17,6 +17,7 @@ fn main() {
 fn run() -> Result<(), Box<dyn Error>> {
     let video_cache = "/tmp/video_cache";
     let output = Path::new("/tmp/output");
+    std::fs::create_dir_all(output)?;
 
     // Use ffmpeg::new(ffmpeg, ffprobe) if you wish to change the default binary path
     let ffmpeg = FFmpeg::default();
@@ -59,27 +60,17 @@ fn run() -> Result<(), Box<dyn Error>> {
         .effect(Effect::AudioGain { gain: 0.5 })
     );
 
-    // Render
+    // Render & encode
     let renderer = Arc::new(Renderer::new(editor));
-    let renderer = renderer.render_full_multithreaded(output.join("frames"), num_cpus::get())?;
-    renderer.render_audio_wav(output.join("audio.wav"), 44100, 2)?;
-    
-    // Merge
-    ffmpeg.convert(output.join("frames").join("%06d.png"), output.join("output.mp4"), [
-        // Add audio
-        "-i", &output.join("audio.wav").to_string_lossy().to_string(),
+    renderer.render_encoded(&ffmpeg, output.join("output.mp4"), num_cpus::get(), 44100, 2, [
         // Video encoding parameters
-        "-c:v", "libx264", "-vf", "fps=25", "-pix_fmt", "yuv420p", "-b:v", "600k",
+        "-c:v", "libx264", "-pix_fmt", "yuv420p", "-b:v", "600k",
         // Audio encoding parameters
         "-b:a", "128k", "-c:a", "aac", "-ar", "44100",
         // Streaming
         "-movflags", "+faststart"
     ])?;
 
-    // Clean temp
-    std::fs::remove_dir_all(output.join("frames"))?;
-    std::fs::remove_file(output.join("audio.wav"))?;
-
     log::info!("Saved in: {:?}", output.join("output.mp4"));
     Ok(())
 }
\ No newline at end of file
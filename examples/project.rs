@@ -0,0 +1,58 @@
+use std::error::Error;
+use std::f32::consts::PI;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use videoeditor::{FFmpeg, FileLoader, Project};
+use videoeditor::editor::{Transform, Effect, Renderer};
+use videoeditor::project::ProjectLayer;
+
+fn main() {
+    std::env::set_var("RUST_LOG", "debug");
+    pretty_env_logger::init();
+
+    run().expect("Failed");
+}
+
+fn run() -> Result<(), Box<dyn Error>> {
+    let output = Path::new("/tmp/output");
+    std::fs::create_dir_all(output)?;
+
+    // Paths in project files are relative to the file, which is saved elsewhere
+    let assets = std::fs::canonicalize("assets")?;
+
+    // Describe the timeline
+    let project = Project::new(640, 360, Duration::from_secs(10), 25.0)
+    .layer(
+        ProjectLayer::new(assets.join("sample.m4v"), Duration::ZERO, Transform::ZERO)
+        .effect(Effect::ScaleToBase { force: true })
+    )
+    .layer(
+        ProjectLayer::new(assets.join("sample.png"), Duration::from_secs(5), Transform::Percent(0.5, 0.5))
+        .effect(Effect::ScaleOverTime { x0: 1.0, y0: 1.0, x1: 2.0, y1: 2.0 })
+        .effect(Effect::RotateOverTime { a0: 0.0, a1: PI, uncropped: true })
+    )
+    .layer(
+        ProjectLayer::new(assets.join("sample.mp3"), Duration::from_secs(5), Transform::ZERO)
+        .speed(0.5)
+        .effect(Effect::AudioGain { gain: 0.5 })
+    );
+
+    // Save and load it back
+    project.save(output.join("project.json"))?;
+    let project = Project::load(output.join("project.json"))?;
+
+    // Build the editor
+    let ffmpeg = FFmpeg::default();
+    let loader = FileLoader::new("/tmp/video_cache", ffmpeg.clone());
+    let editor = project.editor(&loader)?;
+
+    // Render
+    let renderer = Arc::new(Renderer::new(editor));
+    renderer.render_full_multithreaded(output.join("frames"), num_cpus::get())?;
+    renderer.render_audio_wav(output.join("audio.wav"), 44100, 2)?;
+
+    log::info!("Rendered: {:?}", output);
+    Ok(())
+}
@@ -0,0 +1,193 @@
+use image::{DynamicImage, GrayImage, ImageBuffer, Pixel, RgbaImage};
+use image::imageops::{overlay, resize, FilterType};
+use serde::{Serialize, Deserialize};
+
+/// How is a layer combined with the layers below
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub enum BlendMode {
+    /// Alpha over
+    #[default]
+    Normal,
+    Multiply,
+    Screen,
+    Add,
+    Overlay,
+    Darken,
+    Lighten,
+    Difference
+}
+
+impl BlendMode {
+    /// Blend single channel, base & source in 0 -> 1
+    fn apply(&self, b: f32, s: f32) -> f32 {
+        match self {
+            BlendMode::Normal => s,
+            BlendMode::Multiply => b * s,
+            BlendMode::Screen => b + s - b * s,
+            BlendMode::Add => (b + s).min(1.0),
+            BlendMode::Overlay => match b <= 0.5 {
+                true => 2.0 * b * s,
+                false => 1.0 - 2.0 * (1.0 - b) * (1.0 - s)
+            },
+            BlendMode::Darken => b.min(s),
+            BlendMode::Lighten => b.max(s),
+            BlendMode::Difference => (b - s).abs(),
+        }
+    }
+}
+
+/// Composite frame on top of base at position with opacity (0 -> 1) and blend mode
+pub fn composite(base: &mut DynamicImage, frame: &DynamicImage, x: i64, y: i64, opacity: f32, mode: BlendMode) {
+    // Fast path
+    if mode == BlendMode::Normal && opacity >= 1.0 {
+        overlay(base, frame, x, y);
+        return;
+    }
+    if opacity <= 0.0 {
+        return;
+    }
+
+    let frame = frame.to_rgba8();
+    match base {
+        DynamicImage::ImageRgb8(base) => composite_buffer(base, &frame, x, y, opacity, mode),
+        DynamicImage::ImageRgba8(base) => composite_buffer(base, &frame, x, y, opacity, mode),
+        _ => {
+            let mut rgba = base.to_rgba8();
+            composite_buffer(&mut rgba, &frame, x, y, opacity, mode);
+            *base = rgba.into();
+        }
+    }
+}
+
+/// Composite RGBA frame over 8bit RGB or RGBA buffer
+fn composite_buffer<P>(base: &mut ImageBuffer<P, Vec<u8>>, frame: &RgbaImage, x: i64, y: i64, opacity: f32, mode: BlendMode)
+where
+    P: Pixel<Subpixel = u8>
+{
+    let has_alpha = P::CHANNEL_COUNT == 4;
+    // Visible region
+    let x0 = x.max(0);
+    let y0 = y.max(0);
+    let x1 = (x + frame.width() as i64).min(base.width() as i64);
+    let y1 = (y + frame.height() as i64).min(base.height() as i64);
+
+    for by in y0..y1 {
+        for bx in x0..x1 {
+            let src = frame.get_pixel((bx - x) as u32, (by - y) as u32);
+            let a = src[3] as f32 / 255.0 * opacity;
+            if a <= 0.0 {
+                continue;
+            }
+            let dst = base.get_pixel_mut(bx as u32, by as u32).channels_mut();
+            let ab = match has_alpha {
+                true => dst[3] as f32 / 255.0,
+                false => 1.0
+            };
+            let out_a = a + ab * (1.0 - a);
+            for c in 0..3 {
+                let b = dst[c] as f32 / 255.0;
+                let s = src[c] as f32 / 255.0;
+                // Blend only where the base is opaque
+                let mixed = (1.0 - ab) * s + ab * mode.apply(b, s);
+                let out = (mixed * a + b * ab * (1.0 - a)) / out_a;
+                dst[c] = (out * 255.0).round().clamp(0.0, 255.0) as u8;
+            }
+            if has_alpha {
+                dst[3] = (out_a * 255.0).round() as u8;
+            }
+        }
+    }
+}
+
+/// Multiply alpha channel of frame by factor (0 -> 1)
+pub fn multiply_alpha(frame: DynamicImage, factor: f32) -> DynamicImage {
+    if factor >= 1.0 {
+        return frame;
+    }
+    let mut frame = frame.into_rgba8();
+    for pixel in frame.pixels_mut() {
+        pixel[3] = (pixel[3] as f32 * factor.max(0.0)).round() as u8;
+    }
+    frame.into()
+}
+
+/// Multiply alpha by mask (white = visible), mask is stretched to the frame size
+pub fn apply_mask(frame: DynamicImage, mask: &GrayImage) -> DynamicImage {
+    let mut frame = frame.into_rgba8();
+    let resized;
+    let mask = match mask.dimensions() == frame.dimensions() {
+        true => mask,
+        false => {
+            resized = resize(mask, frame.width(), frame.height(), FilterType::Triangle);
+            &resized
+        }
+    };
+    for (pixel, m) in frame.pixels_mut().zip(mask.pixels()) {
+        pixel[3] = (pixel[3] as u16 * m[0] as u16 / 255) as u8;
+    }
+    frame.into()
+}
+
+/// Basic color correction: brightness is added (-255 -> 255), contrast in percent,
+/// saturation multiplies the chroma (1 = unchanged), hue is rotated in degrees
+pub fn color_adjust(frame: DynamicImage, brightness: i32, contrast: f32, saturation: f32, hue: i32) -> DynamicImage {
+    let mut frame = frame.brighten(brightness).adjust_contrast(contrast).huerotate(hue).into_rgba8();
+    if saturation != 1.0 {
+        for pixel in frame.pixels_mut() {
+            let [r, g, b] = [pixel[0], pixel[1], pixel[2]].map(|c| c as f32);
+            let luma = 0.299 * r + 0.587 * g + 0.114 * b;
+            for c in 0..3 {
+                pixel[c] = (luma + (pixel[c] as f32 - luma) * saturation).round().clamp(0.0, 255.0) as u8;
+            }
+        }
+    }
+    frame.into()
+}
+
+/// Convert RGB to (Y, Cb, Cr) in 0 -> 1, chroma centered at 0
+fn ycbcr(r: f32, g: f32, b: f32) -> (f32, f32, f32) {
+    (
+        0.299 * r + 0.587 * g + 0.114 * b,
+        -0.168736 * r - 0.331264 * g + 0.5 * b,
+        0.5 * r - 0.418688 * g - 0.081312 * b
+    )
+}
+
+/// Key out color: tolerance & softness are chroma distances (0 -> 1), spill (0 -> 1) removes the key color from edges
+pub fn chroma_key(frame: DynamicImage, color: [u8; 3], tolerance: f32, softness: f32, spill: f32) -> DynamicImage {
+    let (_, kb, kr) = ycbcr(color[0] as f32 / 255.0, color[1] as f32 / 255.0, color[2] as f32 / 255.0);
+    let key_len = (kb * kb + kr * kr).sqrt().max(f32::EPSILON);
+    let (ub, ur) = (kb / key_len, kr / key_len);
+
+    let mut frame = frame.into_rgba8();
+    for pixel in frame.pixels_mut() {
+        let (r, g, b) = (pixel[0] as f32 / 255.0, pixel[1] as f32 / 255.0, pixel[2] as f32 / 255.0);
+        let (y, cb, cr) = ycbcr(r, g, b);
+        // Distance from key in chroma plane
+        let distance = ((cb - kb).powi(2) + (cr - kr).powi(2)).sqrt();
+        let alpha = match softness <= 0.0 {
+            true => if distance > tolerance { 1.0 } else { 0.0 },
+            false => ((distance - tolerance) / softness).clamp(0.0, 1.0)
+        };
+        if alpha <= 0.0 {
+            pixel[3] = 0;
+            continue;
+        }
+        pixel[3] = (pixel[3] as f32 * alpha).round() as u8;
+
+        // Remove the key color component from chroma
+        let projection = cb * ub + cr * ur;
+        if spill > 0.0 && projection > 0.0 {
+            let (cb, cr) = (cb - ub * projection * spill, cr - ur * projection * spill);
+            let rgb = [
+                y + 1.402 * cr,
+                y - 0.344136 * cb - 0.714136 * cr,
+                y + 1.772 * cb
+            ];
+            for c in 0..3 {
+                pixel[c] = (rgb[c] * 255.0).round().clamp(0.0, 255.0) as u8;
+            }
+        }
+    }
+    frame.into()
+}
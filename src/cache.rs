@@ -0,0 +1,195 @@
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::Error;
+use fs2::FileExt;
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+
+/// Identity of a cached source file, the cache is stale if any of it changes
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheKey {
+    /// Canonical path of the source
+    pub source: PathBuf,
+    pub size: u64,
+    /// Modification time in nanoseconds since UNIX epoch
+    pub modified: u128,
+}
+
+impl CacheKey {
+    /// Generate key of file
+    pub fn new(path: impl AsRef<Path>) -> Result<CacheKey, Error> {
+        let source = std::fs::canonicalize(path)?;
+        let metadata = std::fs::metadata(&source)?;
+        let modified = metadata.modified()?.duration_since(UNIX_EPOCH)?.as_nanos();
+        Ok(CacheKey { source, size: metadata.len(), modified })
+    }
+
+    /// Name of the cache directory, unique per source path
+    pub fn dir_name(&self) -> String {
+        let stem = self.source.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+        let hash = Sha256::digest(self.source.to_string_lossy().as_bytes());
+        let hash = hash[..8].iter().map(|b| format!("{b:02x}")).collect::<String>();
+        format!("{stem}-{hash}")
+    }
+}
+
+/// Cached video
+#[derive(Debug, Clone)]
+pub struct CacheEntry {
+    pub path: PathBuf,
+    /// None for entries created without a key
+    pub key: Option<CacheKey>,
+    /// Size on disk in bytes
+    pub size: u64,
+    pub last_used: SystemTime,
+}
+
+/// Manages the video cache directory
+#[derive(Debug, Clone)]
+pub struct VideoCache {
+    path: PathBuf
+}
+
+impl VideoCache {
+    /// Name of the metadata file inside every entry
+    pub(crate) const META: &'static str = "meta.json";
+
+    /// Create new instance
+    pub fn new(path: impl AsRef<Path>) -> VideoCache {
+        VideoCache { path: path.as_ref().to_owned() }
+    }
+
+    /// Root directory of the cache
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Directory of entry for source file
+    pub fn entry_path(&self, key: &CacheKey) -> PathBuf {
+        self.path.join(key.dir_name())
+    }
+
+    /// List all complete entries
+    pub fn entries(&self) -> Result<Vec<CacheEntry>, Error> {
+        let read_dir = match std::fs::read_dir(&self.path) {
+            Ok(r) => r,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e.into())
+        };
+        let mut entries = vec![];
+        for entry in read_dir {
+            let path = entry?.path();
+            let meta_path = path.join(VideoCache::META);
+            if !path.is_dir() || !meta_path.exists() || is_temp(&path) {
+                continue;
+            }
+            let key = std::fs::read_to_string(&meta_path).ok()
+                .and_then(|data| serde_json::from_str::<serde_json::Value>(&data).ok())
+                .and_then(|meta| serde_json::from_value(meta.get("key")?.clone()).ok());
+            entries.push(CacheEntry {
+                size: dir_size(&path)?,
+                last_used: std::fs::metadata(&meta_path)?.modified()?,
+                key,
+                path,
+            });
+        }
+        Ok(entries)
+    }
+
+    /// Total size of all entries in bytes
+    pub fn disk_usage(&self) -> Result<u64, Error> {
+        Ok(self.entries()?.iter().map(|e| e.size).sum())
+    }
+
+    /// Evict least recently used entries until the cache fits into max bytes, returns evicted entries.
+    /// Don't call while entries are being rendered from.
+    pub fn enforce_limit(&self, max_bytes: u64) -> Result<Vec<CacheEntry>, Error> {
+        let mut entries = self.entries()?;
+        entries.sort_by_key(|e| e.last_used);
+        let mut usage: u64 = entries.iter().map(|e| e.size).sum();
+        let mut evicted = vec![];
+        for entry in entries {
+            if usage <= max_bytes {
+                break;
+            }
+            debug!("Evicting cache entry: {:?}", entry.path);
+            std::fs::remove_dir_all(&entry.path)?;
+            usage -= entry.size;
+            evicted.push(entry);
+        }
+        Ok(evicted)
+    }
+
+    /// Delete the whole cache
+    pub fn purge(&self) -> Result<(), Error> {
+        match std::fs::remove_dir_all(&self.path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(())
+        }
+    }
+
+    /// Lock entry for writing, blocks until other producers are done
+    pub(crate) fn lock(entry: &Path) -> Result<File, Error> {
+        if let Some(parent) = entry.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let file = File::create(sibling(entry, ".lock"))?;
+        file.lock_exclusive()?;
+        Ok(file)
+    }
+
+    /// New unique temporary directory for entry
+    pub(crate) fn temp_path(entry: &Path) -> PathBuf {
+        sibling(entry, &format!(".tmp-{}-{:08x}", std::process::id(), rand::random::<u32>()))
+    }
+
+    /// Remove temporary directories left by crashed producers, has to be called with lock held
+    pub(crate) fn clean_temp(entry: &Path) -> Result<(), Error> {
+        let (parent, name) = match (entry.parent(), entry.file_name()) {
+            (Some(parent), Some(name)) => (parent, name.to_string_lossy()),
+            _ => return Ok(())
+        };
+        let prefix = format!("{name}.tmp-");
+        for e in std::fs::read_dir(parent)? {
+            let e = e?;
+            if e.file_name().to_string_lossy().starts_with(&prefix) {
+                warn!("Removing leftover cache directory: {:?}", e.path());
+                std::fs::remove_dir_all(e.path())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Mark entry as recently used
+    pub(crate) fn touch(entry: impl AsRef<Path>) -> Result<(), Error> {
+        File::options().write(true).open(entry.as_ref().join(VideoCache::META))?.set_modified(SystemTime::now())?;
+        Ok(())
+    }
+}
+
+/// Path next to entry with suffix appended to its name
+fn sibling(entry: &Path, suffix: &str) -> PathBuf {
+    let mut name = entry.file_name().map(|n| n.to_owned()).unwrap_or_default();
+    name.push(suffix);
+    entry.with_file_name(name)
+}
+
+/// Is directory an unfinished entry
+fn is_temp(path: &Path) -> bool {
+    path.file_name().map(|n| n.to_string_lossy().contains(".tmp-")).unwrap_or(false)
+}
+
+/// Recursive size of directory
+fn dir_size(path: &Path) -> Result<u64, Error> {
+    let mut size = 0;
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        size += match metadata.is_dir() {
+            true => dir_size(&entry.path())?,
+            false => metadata.len()
+        };
+    }
+    Ok(size)
+}
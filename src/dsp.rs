@@ -0,0 +1,444 @@
+use std::f64::consts::{FRAC_1_SQRT_2, PI};
+use std::time::Duration;
+use rodio::Source;
+
+use crate::keyframe::Keyframes;
+use crate::time::Timestamp;
+
+/// Frames between updates of animated parameters
+const CONTROL_RATE: u64 = 64;
+
+/// Processes audio frame by frame (one sample of every channel)
+pub trait AudioProcessor {
+    /// Update animated parameters, time is relative to the start of the layer
+    fn update(&mut self, _time: Duration) {}
+    /// Process single frame in place
+    fn process(&mut self, frame: &mut [f32]);
+}
+
+/// Source wrapper running processor over whole frames
+pub(crate) struct Processed<P> {
+    source: Box<dyn Source<Item = f32> + Send + Sync>,
+    processor: P,
+    frame: Vec<f32>,
+    channel: usize,
+    /// Processed frames
+    frames: u64,
+}
+
+impl<P: AudioProcessor> Processed<P> {
+    pub(crate) fn new(source: Box<dyn Source<Item = f32> + Send + Sync>, processor: P) -> Processed<P> {
+        Processed { source, processor, frame: vec![], channel: 0, frames: 0 }
+    }
+}
+
+impl<P: AudioProcessor> Iterator for Processed<P> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.channel == self.frame.len() {
+            self.frame.clear();
+            for _ in 0..self.source.channels() {
+                match self.source.next() {
+                    Some(sample) => self.frame.push(sample),
+                    None => break
+                }
+            }
+            if self.frame.is_empty() {
+                return None;
+            }
+            if self.frames.is_multiple_of(CONTROL_RATE) {
+                let time = Timestamp::from_samples(self.frames as usize, self.source.sample_rate()).to_duration();
+                self.processor.update(time);
+            }
+            self.processor.process(&mut self.frame);
+            self.frames += 1;
+            self.channel = 0;
+        }
+        self.channel += 1;
+        Some(self.frame[self.channel - 1])
+    }
+}
+
+impl<P: AudioProcessor> Source for Processed<P> {
+    fn current_frame_len(&self) -> Option<usize> {
+        self.source.current_frame_len().map(|l| l + self.frame.len() - self.channel)
+    }
+
+    fn channels(&self) -> u16 {
+        self.source.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.source.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.source.total_duration()
+    }
+}
+
+/// Value of animated parameter, or default if it has no keyframes
+fn value(keyframes: &Keyframes<f32>, time: Duration, default: f32) -> f32 {
+    keyframes.value(time).unwrap_or(default)
+}
+
+fn db_to_gain(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+#[derive(Debug, Clone, Copy)]
+enum FilterKind {
+    LowPass,
+    HighPass,
+    LowShelf,
+}
+
+/// Biquad filter with animated frequency
+pub struct Filter {
+    kind: FilterKind,
+    frequency: Keyframes<f32>,
+    /// Shelf gain in dB
+    gain: Keyframes<f32>,
+    sample_rate: u32,
+    /// Q of each cascaded stage
+    q: Vec<f64>,
+    /// Cascaded stages per channel
+    stages: Vec<Vec<Biquad>>,
+}
+
+impl Filter {
+    /// Two stage Butterworth, 24 dB/octave
+    const BUTTERWORTH: [f64; 2] = [0.541_196_1, 1.306_563];
+
+    /// 24 dB/octave low pass, cutoff in Hz
+    pub fn low_pass(cutoff: Keyframes<f32>, sample_rate: u32, channels: u16) -> Filter {
+        Filter::new(FilterKind::LowPass, cutoff, Keyframes::new(), sample_rate, channels, Self::BUTTERWORTH.to_vec())
+    }
+
+    /// 24 dB/octave high pass, cutoff in Hz
+    pub fn high_pass(cutoff: Keyframes<f32>, sample_rate: u32, channels: u16) -> Filter {
+        Filter::new(FilterKind::HighPass, cutoff, Keyframes::new(), sample_rate, channels, Self::BUTTERWORTH.to_vec())
+    }
+
+    /// Boost or cut below frequency in Hz by gain in dB
+    pub fn low_shelf(frequency: Keyframes<f32>, gain: Keyframes<f32>, sample_rate: u32, channels: u16) -> Filter {
+        Filter::new(FilterKind::LowShelf, frequency, gain, sample_rate, channels, vec![FRAC_1_SQRT_2])
+    }
+
+    fn new(kind: FilterKind, frequency: Keyframes<f32>, gain: Keyframes<f32>, sample_rate: u32, channels: u16, q: Vec<f64>) -> Filter {
+        let stages = vec![vec![Biquad::new([1.0, 0.0, 0.0], [0.0, 0.0]); q.len()]; channels as usize];
+        Filter { kind, frequency, gain, sample_rate, q, stages }
+    }
+
+    /// RBJ cookbook coefficients
+    fn coefficients(&self, frequency: f32, q: f64, gain: f32) -> ([f64; 3], [f64; 2]) {
+        let rate = self.sample_rate as f64;
+        let w = 2.0 * PI * (frequency as f64).clamp(10.0, rate * 0.49) / rate;
+        let (sin, cos) = w.sin_cos();
+        let alpha = sin / (2.0 * q);
+        let (b, a) = match self.kind {
+            FilterKind::LowPass => ([(1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0], [1.0 + alpha, -2.0 * cos, 1.0 - alpha]),
+            FilterKind::HighPass => ([(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0], [1.0 + alpha, -2.0 * cos, 1.0 - alpha]),
+            FilterKind::LowShelf => {
+                let a = 10f64.powf(gain as f64 / 40.0);
+                let beta = 2.0 * a.sqrt() * alpha;
+                (
+                    [a * ((a + 1.0) - (a - 1.0) * cos + beta), 2.0 * a * ((a - 1.0) - (a + 1.0) * cos), a * ((a + 1.0) - (a - 1.0) * cos - beta)],
+                    [(a + 1.0) + (a - 1.0) * cos + beta, -2.0 * ((a - 1.0) + (a + 1.0) * cos), (a + 1.0) + (a - 1.0) * cos - beta]
+                )
+            }
+        };
+        (b.map(|b| b / a[0]), [a[1] / a[0], a[2] / a[0]])
+    }
+}
+
+impl AudioProcessor for Filter {
+    fn update(&mut self, time: Duration) {
+        let (frequency, gain) = (value(&self.frequency, time, 100.0), value(&self.gain, time, 0.0));
+        for i in 0..self.q.len() {
+            let (b, a) = self.coefficients(frequency, self.q[i], gain);
+            self.stages.iter_mut().for_each(|s| s[i].set(b, a));
+        }
+    }
+
+    fn process(&mut self, frame: &mut [f32]) {
+        for (sample, stages) in frame.iter_mut().zip(&mut self.stages) {
+            *sample = stages.iter_mut().fold(*sample as f64, |s, stage| stage.process(s)) as f32;
+        }
+    }
+}
+
+/// Repeats of the input after delay, the tail is cut off where the input ends
+pub struct Echo {
+    feedback: Keyframes<f32>,
+    mix: Keyframes<f32>,
+    current: (f32, f32),
+    /// Delay line per channel
+    buffers: Vec<Vec<f32>>,
+    index: usize,
+}
+
+impl Echo {
+    /// Feedback (0 -> 1) = level of each next repeat, mix = level of the echoes
+    pub fn new(delay: Duration, feedback: Keyframes<f32>, mix: Keyframes<f32>, sample_rate: u32, channels: u16) -> Echo {
+        let length = ((delay.as_secs_f64() * sample_rate as f64) as usize).max(1);
+        Echo { feedback, mix, current: (0.0, 0.0), buffers: vec![vec![0.0; length]; channels as usize], index: 0 }
+    }
+}
+
+impl AudioProcessor for Echo {
+    fn update(&mut self, time: Duration) {
+        self.current = (value(&self.feedback, time, 0.5).clamp(0.0, 0.95), value(&self.mix, time, 0.5));
+    }
+
+    fn process(&mut self, frame: &mut [f32]) {
+        let (feedback, mix) = self.current;
+        for (sample, buffer) in frame.iter_mut().zip(&mut self.buffers) {
+            let delayed = buffer[self.index];
+            buffer[self.index] = *sample + delayed * feedback;
+            *sample += delayed * mix;
+        }
+        self.index = (self.index + 1) % self.buffers.first().map(|b| b.len()).unwrap_or(1);
+    }
+}
+
+/// Freeverb style reverb, parallel combs followed by allpasses. The tail is cut off where the input ends.
+pub struct Reverb {
+    room_size: Keyframes<f32>,
+    damping: Keyframes<f32>,
+    mix: Keyframes<f32>,
+    current: (f32, f32, f32),
+    combs: Vec<Vec<Comb>>,
+    allpasses: Vec<Vec<Allpass>>,
+}
+
+impl Reverb {
+    /// Delays in samples at 44.1kHz
+    const COMBS: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+    const ALLPASSES: [usize; 4] = [556, 441, 341, 225];
+    /// Offset of delays between channels, for width
+    const SPREAD: usize = 23;
+
+    /// Room size, damping of high frequencies & mix of the reverb, all 0 -> 1
+    pub fn new(room_size: Keyframes<f32>, damping: Keyframes<f32>, mix: Keyframes<f32>, sample_rate: u32, channels: u16) -> Reverb {
+        let scale = |length: usize, channel: usize| (((length + channel * Self::SPREAD) as f64 * sample_rate as f64 / 44100.0) as usize).max(1);
+        let combs = (0..channels as usize).map(|c| Self::COMBS.iter().map(|l| Comb::new(scale(*l, c))).collect()).collect();
+        let allpasses = (0..channels as usize).map(|c| Self::ALLPASSES.iter().map(|l| Allpass::new(scale(*l, c))).collect()).collect();
+        Reverb { room_size, damping, mix, current: (0.5, 0.5, 0.3), combs, allpasses }
+    }
+}
+
+impl AudioProcessor for Reverb {
+    fn update(&mut self, time: Duration) {
+        self.current = (
+            0.7 + 0.28 * value(&self.room_size, time, 0.5).clamp(0.0, 1.0),
+            0.4 * value(&self.damping, time, 0.5).clamp(0.0, 1.0),
+            value(&self.mix, time, 0.3).clamp(0.0, 1.0)
+        );
+    }
+
+    fn process(&mut self, frame: &mut [f32]) {
+        let (feedback, damping, mix) = self.current;
+        for (c, sample) in frame.iter_mut().enumerate() {
+            let input = *sample * 0.015;
+            let wet = self.combs[c].iter_mut().map(|comb| comb.process(input, feedback, damping)).sum::<f32>();
+            let wet = self.allpasses[c].iter_mut().fold(wet, |s, allpass| allpass.process(s));
+            *sample = *sample * (1.0 - mix) + wet * 3.0 * mix;
+        }
+    }
+}
+
+/// Lowpass feedback comb filter
+struct Comb {
+    buffer: Vec<f32>,
+    index: usize,
+    filter: f32,
+}
+
+impl Comb {
+    fn new(length: usize) -> Comb {
+        Comb { buffer: vec![0.0; length], index: 0, filter: 0.0 }
+    }
+
+    fn process(&mut self, input: f32, feedback: f32, damping: f32) -> f32 {
+        let output = self.buffer[self.index];
+        self.filter = output * (1.0 - damping) + self.filter * damping;
+        self.buffer[self.index] = input + self.filter * feedback;
+        self.index = (self.index + 1) % self.buffer.len();
+        output
+    }
+}
+
+struct Allpass {
+    buffer: Vec<f32>,
+    index: usize,
+}
+
+impl Allpass {
+    fn new(length: usize) -> Allpass {
+        Allpass { buffer: vec![0.0; length], index: 0 }
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        let delayed = self.buffer[self.index];
+        self.buffer[self.index] = input + delayed * 0.5;
+        self.index = (self.index + 1) % self.buffer.len();
+        delayed - input
+    }
+}
+
+/// Reduce bit depth & sample rate
+pub struct Bitcrush {
+    bits: Keyframes<f32>,
+    downsample: Keyframes<f32>,
+    current: (f32, f32),
+    held: Vec<f32>,
+    /// Frames until the next held one
+    phase: f32,
+}
+
+impl Bitcrush {
+    /// Bits per sample (1 -> 24), downsample = keep every nth frame (>= 1)
+    pub fn new(bits: Keyframes<f32>, downsample: Keyframes<f32>, channels: u16) -> Bitcrush {
+        Bitcrush { bits, downsample, current: (8.0, 1.0), held: vec![0.0; channels as usize], phase: 0.0 }
+    }
+}
+
+impl AudioProcessor for Bitcrush {
+    fn update(&mut self, time: Duration) {
+        self.current = (value(&self.bits, time, 8.0).clamp(1.0, 24.0), value(&self.downsample, time, 1.0).max(1.0));
+    }
+
+    fn process(&mut self, frame: &mut [f32]) {
+        let (bits, downsample) = self.current;
+        if self.phase <= 0.0 {
+            self.phase += downsample;
+            let levels = 2f32.powf(bits - 1.0);
+            for (held, sample) in self.held.iter_mut().zip(frame.iter()) {
+                *held = (sample * levels).round() / levels;
+            }
+        }
+        self.phase -= 1.0;
+        frame.iter_mut().zip(&self.held).for_each(|(s, h)| *s = *h);
+    }
+}
+
+/// Saturation by overdriving a tanh curve
+pub struct Distortion {
+    drive: Keyframes<f32>,
+    mix: Keyframes<f32>,
+    current: (f32, f32),
+}
+
+impl Distortion {
+    /// Drive in dB, mix of the distorted signal 0 -> 1
+    pub fn new(drive: Keyframes<f32>, mix: Keyframes<f32>) -> Distortion {
+        Distortion { drive, mix, current: (1.0, 1.0) }
+    }
+}
+
+impl AudioProcessor for Distortion {
+    fn update(&mut self, time: Duration) {
+        self.current = (db_to_gain(value(&self.drive, time, 20.0)), value(&self.mix, time, 1.0).clamp(0.0, 1.0));
+    }
+
+    fn process(&mut self, frame: &mut [f32]) {
+        let (drive, mix) = self.current;
+        for sample in frame {
+            *sample = *sample * (1.0 - mix) + (*sample * drive).tanh() * mix;
+        }
+    }
+}
+
+/// Direct form I biquad, `a` is normalized by a0
+#[derive(Debug, Clone)]
+pub(crate) struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    pub(crate) fn new(b: [f64; 3], a: [f64; 2]) -> Biquad {
+        Biquad { b, a, x: [0.0; 2], y: [0.0; 2] }
+    }
+
+    /// Change coefficients, keeping the state
+    pub(crate) fn set(&mut self, b: [f64; 3], a: [f64; 2]) {
+        self.b = b;
+        self.a = a;
+    }
+
+    pub(crate) fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1] - self.a[0] * self.y[0] - self.a[1] * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run processor over mono samples
+    fn run(mut processor: impl AudioProcessor, samples: &[f32]) -> Vec<f32> {
+        processor.update(Duration::ZERO);
+        samples.iter().map(|s| {
+            let mut frame = [*s];
+            processor.process(&mut frame);
+            frame[0]
+        }).collect()
+    }
+
+    /// Level of sine at frequency after filtering, skipping the settling
+    fn response(filter: Filter, frequency: f64) -> f32 {
+        let sine = (0..48000).map(|i| (2.0 * PI * frequency * i as f64 / 48000.0).sin() as f32).collect::<Vec<_>>();
+        let output = run(filter, &sine);
+        let rms = |s: &[f32]| (s.iter().map(|s| s * s).sum::<f32>() / s.len() as f32).sqrt();
+        rms(&output[4800..]) / rms(&sine[4800..])
+    }
+
+    #[test]
+    fn low_pass() {
+        let filter = || Filter::low_pass(Keyframes::constant(1000.0), 48000, 1);
+        assert!((response(filter(), 100.0) - 1.0).abs() < 0.02);
+        // 12dB per octave above the cutoff
+        assert!(response(filter(), 8000.0) < 0.02);
+    }
+
+    #[test]
+    fn high_pass() {
+        let filter = || Filter::high_pass(Keyframes::constant(1000.0), 48000, 1);
+        assert!(response(filter(), 100.0) < 0.02);
+        assert!((response(filter(), 8000.0) - 1.0).abs() < 0.02);
+    }
+
+    #[test]
+    fn low_shelf() {
+        let filter = || Filter::low_shelf(Keyframes::constant(200.0), Keyframes::constant(12.0), 48000, 1);
+        assert!((response(filter(), 30.0) - db_to_gain(12.0)).abs() < 0.2);
+        assert!((response(filter(), 8000.0) - 1.0).abs() < 0.02);
+    }
+
+    #[test]
+    fn echo_delay() {
+        let mut impulse = vec![0.0; 50];
+        impulse[0] = 1.0;
+        let echo = Echo::new(Duration::from_millis(10), Keyframes::constant(0.5), Keyframes::constant(0.8), 1000, 1);
+        let output = run(echo, &impulse);
+        for (i, sample) in output.iter().enumerate() {
+            let expected = match i {
+                0 => 1.0,
+                10 => 0.8,
+                20 => 0.4,
+                30 => 0.2,
+                40 => 0.1,
+                _ => 0.0
+            };
+            assert!((sample - expected).abs() < 1e-6, "sample {i}: {sample}");
+        }
+    }
+}
@@ -1,3 +1,6 @@
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::panic::{catch_unwind, AssertUnwindSafe};
 use std::f32::consts::PI;
 use std::path::Path;
 use std::sync::Arc;
@@ -5,27 +8,56 @@ use std::time::Duration;
 use anyhow::Error;
 use crossbeam_channel::unbounded;
 use hound::{WavSpec, SampleFormat, WavWriter};
-use image::{DynamicImage, Rgba, RgbImage};
+use image::{DynamicImage, GrayImage, Rgba, RgbImage, RgbaImage};
 use image::imageops::{overlay, FilterType};
 use imageproc::geometric_transformations::{rotate_about_center, Interpolation};
 use lerp::Lerp;
 use rodio::Source;
+use rodio::buffer::SamplesBuffer;
 use rodio::source::UniformSourceIterator;
+use serde::{Serialize, Deserialize};
 use threadpool::ThreadPool;
 
+use crate::ffmpeg::FFmpeg;
+use crate::gif::{GifOptions, GifSink, WebpSink};
+use crate::master::{Ducking, MasterBus};
+use crate::progress::{RenderHooks, Stage, Progress, Cancelled, CancellationToken};
+use crate::report::{ErrorMode, LayerError, RenderReport};
+use crate::blend::{BlendMode, apply_mask, composite, multiply_alpha, chroma_key, color_adjust};
+use crate::keyframe::{Easing, Keyframes};
+use crate::transition::Transition;
+use crate::time::{Rational, Timestamp};
+use crate::stretch::{SpeedMode, time_stretch};
+use crate::dsp::{AudioProcessor, Bitcrush, Distortion, Echo, Filter, Processed, Reverb};
 
-#[derive(Debug, Clone)]
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EditorMeta {
     width: u32,
     height: u32,
-    fps: f32,
+    fps: Rational,
     duration: Duration
 }
 
 impl EditorMeta {
+    /// Create new editor metadata
+    pub fn new(width: u32, height: u32, duration: Duration, fps: impl Into<Rational>) -> EditorMeta {
+        EditorMeta { width, height, duration, fps: fps.into() }
+    }
+
     /// Get frame count
     pub fn frames(&self) -> usize {
-        (self.fps * self.duration.as_secs_f32()) as usize
+        Timestamp::from(self.duration).frame(self.fps) as usize
+    }
+
+    /// Get frame rate
+    pub fn fps(&self) -> Rational {
+        self.fps
+    }
+
+    /// Start of frame
+    pub fn frame_time(&self, index: usize) -> Timestamp {
+        Timestamp::from_frame(index, self.fps)
     }
 }
 
@@ -33,12 +65,31 @@ impl EditorMeta {
 pub struct Editor {
     layers: Vec<Layer>,
     meta: EditorMeta,
+    /// Transitions from layer at index to the next one
+    transitions: Vec<(usize, Transition)>,
+    /// Transparent background instead of black
+    transparent: bool,
 }
 
 impl Editor {
+    /// Audio format of nested editors, the parent converts it to its own
+    const NESTED_SAMPLE_RATE: u32 = 48000;
+    const NESTED_CHANNELS: u16 = 2;
+
     /// Create new editor instance
-    pub fn new(width: u32, height: u32, duration: Duration, fps: f32) -> Editor {
-        Editor { layers: vec![], meta: EditorMeta { width, height, duration, fps } }
+    pub fn new(width: u32, height: u32, duration: Duration, fps: impl Into<Rational>) -> Editor {
+        Editor::with_meta(EditorMeta::new(width, height, duration, fps))
+    }
+
+    /// Create new editor instance from existing metadata
+    pub fn with_meta(meta: EditorMeta) -> Editor {
+        Editor { layers: vec![], meta, transitions: vec![], transparent: false }
+    }
+
+    /// Start with a transparent instead of black frame, for nesting as a layer
+    pub fn transparent(mut self, transparent: bool) -> Self {
+        self.transparent = transparent;
+        self
     }
 
     /// Add new layer
@@ -46,77 +97,496 @@ impl Editor {
         self.layers.push(layer);
         self
     }
+
+    /// Add transition between the last added layer and the next one
+    pub fn transition(self, transition: Transition) -> Self {
+        let index = self.layers.len().checked_sub(1).expect("Transition has to follow a layer");
+        self.transition_after(index, transition)
+    }
+
+    /// Add transition between layer at index and the one after it
+    pub fn transition_after(mut self, index: usize, transition: Transition) -> Self {
+        self.transitions.retain(|(i, _)| *i != index);
+        self.transitions.push((index, transition));
+        self
+    }
+
+    /// Get transition from layer at index into the next one
+    fn transition_from(&self, index: usize) -> Option<&Transition> {
+        if index + 1 >= self.layers.len() {
+            return None;
+        }
+        self.transitions.iter().find(|(i, _)| *i == index).map(|(_, t)| t)
+    }
+
+    /// Get audio gain envelope of layer caused by transitions, relative to the layer start
+    fn transition_gain(&self, index: usize) -> Option<impl Fn(Duration) -> f32 + Send + Sync + 'static> {
+        let layer = &self.layers[index];
+        let mut fades = vec![];
+        // Fade out
+        if let Some(transition) = self.transition_from(index).filter(|t| t.audio) {
+            fades.push(((self.layers[index + 1].offset - layer.offset).to_duration(), *transition, false));
+        }
+        // Fade in
+        if let Some(transition) = index.checked_sub(1).and_then(|i| self.transition_from(i)).filter(|t| t.audio) {
+            fades.push((Duration::ZERO, *transition, true));
+        }
+        if fades.is_empty() {
+            return None;
+        }
+        Some(move |t: Duration| {
+            fades.iter().map(|(start, transition, incoming)| {
+                if t < *start {
+                    return if *incoming { 0.0 } else { 1.0 };
+                }
+                let (out, into) = transition.audio_gains(transition.progress(t - *start));
+                if *incoming { into } else { out.max(0.0) }
+            }).product()
+        })
+    }
+
+    /// Compose all layers, if errors are provided failing layers get skipped
+    fn compose_frame(&self, frame_index: usize, mut errors: Option<&mut Vec<LayerError>>) -> Result<Option<DynamicImage>, LayerError> {
+        if frame_index >= self.meta.frames() {
+            return Ok(None);
+        }
+
+        // Create base frame
+        let mut base = match self.transparent {
+            true => DynamicImage::from(RgbaImage::new(self.meta.width, self.meta.height)),
+            false => DynamicImage::from(RgbImage::new(self.meta.width, self.meta.height))
+        };
+        let offset = self.meta.frame_time(frame_index);
+        let layers = &self.layers;
+        // Wrap error with layer index, or skip it
+        let mut check = |layer: usize, result: Result<(), Error>| -> Result<(), LayerError> {
+            match (result, errors.as_mut()) {
+                (Ok(_), _) => Ok(()),
+                (Err(error), Some(errors)) => {
+                    errors.push(LayerError { layer, error });
+                    Ok(())
+                },
+                (Err(error), None) => Err(LayerError { layer, error })
+            }
+        };
+        let mut i = 0;
+        while i < layers.len() {
+            if let Some(transition) = self.transition_from(i) {
+                let start = layers[i + 1].offset;
+                // Render both layers and mix them
+                if offset >= start && offset < start + transition.duration {
+                    let mut from = base.clone();
+                    check(i, layers[i].render(offset, &mut from, &self.meta, true))?;
+                    check(i + 1, layers[i + 1].frame(offset, &mut base, &self.meta))?;
+                    base = transition.mix(&from, &base, transition.progress((offset - start).to_duration()));
+                    if from.color() != base.color() {
+                        base = DynamicImage::from(base.to_rgb8());
+                    }
+                    i += 2;
+                    continue;
+                }
+                // Outgoing layer is done
+                if offset >= start + transition.duration {
+                    i += 1;
+                    continue;
+                }
+            }
+            check(i, layers[i].frame(offset, &mut base, &self.meta))?;
+            i += 1;
+        }
+        Ok(Some(base))
+    }
+
+    /// Mix audio of all layers, ducked layers are reduced by the key layers
+    fn mix_audio(&self, sample_rate: u32, channels: u16, ducking: &Ducking, hooks: &RenderHooks) -> Result<Vec<f32>, Error> {
+        // Get sources
+        let mut output = vec![];
+        let mut follower = ducking.follower(sample_rate);
+        let (mut key, mut ducked, mut other) = (vec![0f32; channels as usize], vec![0f32; channels as usize], vec![0f32; channels as usize]);
+        let duration = self.meta.duration;
+        let mut sample: usize = 0;
+        let mut queue = self.layers.iter().enumerate().collect::<Vec<_>>();
+        let mut sources = vec![];
+        let end = Timestamp::from(duration);
+        let tracker = hooks.tracker(Stage::Audio, end.ticks_in(Rational::new(1, sample_rate as i64)) as usize);
+        // Iter over samples
+        loop {
+            // EOF
+            let pos = Timestamp::from_samples(sample, sample_rate);
+            if pos > end {
+                break;
+            }
+            // Report every second
+            if sample.is_multiple_of(sample_rate as usize) {
+                hooks.cancel.check()?;
+                tracker.report(sample);
+            }
+
+            // Find audio source
+            if !queue.is_empty() {
+                let mut i = 0;
+                loop {
+                    if queue[i].1.offset <= pos {
+                        // Make sure they're the same format
+                        let (index, layer) = queue.remove(i);
+                        if let Some(mut src) = layer.audio(sample_rate, channels, &hooks.cancel)? {
+                            // Crossfade
+                            if let Some(gain) = self.transition_gain(index) {
+                                src = src.envelope(gain);
+                            }
+                            sources.push((layer.sidechain, src.source));
+                        }
+                    } else {
+                        i += 1;
+                    }
+                    if i == queue.len() {
+                        break;
+                    }
+                }
+            }
+
+            // Merge audio sources by sidechain role, levels are handled by the master bus
+            for bus in [&mut key, &mut ducked, &mut other] {
+                bus.fill(0.0);
+            }
+            for c in 0..channels as usize {
+                sources.retain_mut(|(sidechain, source)| match source.next() {
+                    Some(s) => {
+                        match sidechain {
+                            Sidechain::None => other[c] += s,
+                            Sidechain::Key => key[c] += s,
+                            Sidechain::Ducked => ducked[c] += s,
+                        }
+                        true
+                    },
+                    None => false
+                });
+            }
+            let gain = follower.process(&key);
+            for c in 0..channels as usize {
+                output.push(other[c] + key[c] + ducked[c] * gain);
+            }
+            sample += 1;
+        }
+
+        Ok(output)
+    }
+}
+
+/// Nested editor (precomp), rendered at its own size & fps
+impl LayerData for Editor {
+    fn duration(&self) -> Duration {
+        self.meta.duration
+    }
+
+    fn frame(&self, offset: Duration) -> Result<Option<DynamicImage>, Error> {
+        let index = Timestamp::from(offset).frame(self.meta.fps) as usize;
+        Ok(self.compose_frame(index, None)?)
+    }
+
+    fn audio(&self) -> Result<Option<AudioData>, Error> {
+        if self.layers.is_empty() {
+            return Ok(None);
+        }
+        let samples = self.mix_audio(Editor::NESTED_SAMPLE_RATE, Editor::NESTED_CHANNELS, &Ducking::default(), &RenderHooks::default())?;
+        Ok(Some(AudioData::new(SamplesBuffer::new(Editor::NESTED_CHANNELS, Editor::NESTED_SAMPLE_RATE, samples))))
+    }
+}
+
+/// Data of adjustment layer, only has duration
+struct AdjustmentData {
+    duration: Duration,
+}
+
+impl LayerData for AdjustmentData {
+    fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    fn frame(&self, _offset: Duration) -> Result<Option<DynamicImage>, Error> {
+        Ok(None)
+    }
+
+    fn audio(&self) -> Result<Option<AudioData>, Error> {
+        Ok(None)
+    }
 }
 
 /// Layer which can be overlayed over other layers in Editor
 pub struct Layer {
-    offset: Duration,
+    offset: Timestamp,
     effects: Vec<Box<dyn EditorEffect + Send + Sync>>,
     transform: Transform,
+    /// Position in the source where the layer starts playing
+    source_in: Duration,
+    /// None = end of the source
+    source_out: Option<Duration>,
+    end: EndBehavior,
+    /// Layer time -> source time
+    remap: Option<Keyframes<Duration>>,
     duration: Duration,
     speed: f32,
+    speed_mode: SpeedMode,
+    sidechain: Sidechain,
+    opacity: f32,
+    blend: BlendMode,
+    /// Apply effects to the layers below instead of own data
+    adjustment: bool,
+    mask: Option<GrayImage>,
     data: Box<dyn LayerData + Send + Sync>
 }
 
 
 impl Layer {
     /// Create new layer
-    pub fn new(data: Box<dyn LayerData + Send + Sync + 'static>, offset: Duration, transform: Transform) -> Layer {
+    pub fn new(data: Box<dyn LayerData + Send + Sync + 'static>, offset: impl Into<Timestamp>, transform: Transform) -> Layer {
         Layer {
             duration: data.duration(),
-            offset,
+            source_in: Duration::ZERO,
+            source_out: None,
+            end: EndBehavior::Hold,
+            remap: None,
+            offset: offset.into(),
             data,
             transform, 
             speed: 1.0,
+            speed_mode: SpeedMode::Resample,
+            sidechain: Sidechain::None,
+            opacity: 1.0,
+            blend: BlendMode::Normal,
+            adjustment: false,
+            mask: None,
             effects: vec![]
         }
     }
 
+    /// Create new adjustment layer, its effects are applied to the composite of the layers below
+    pub fn adjustment(offset: impl Into<Timestamp>, duration: Duration) -> Layer {
+        let mut layer = Layer::new(Box::new(AdjustmentData { duration }), offset, Transform::ZERO);
+        layer.adjustment = true;
+        layer
+    }
+
     /// Add new effect
     pub fn effect(mut self, effect: impl EditorEffect + Send + Sync + 'static) -> Self {
         self.effects.push(Box::new(effect));
         self
     }
 
-    /// Set new duration of this layer
+    /// Set new duration of this layer, see `end_behavior` for durations longer than the source
     pub fn duration(mut self, duration: Duration) -> Self {
-        if duration > self.data.duration() {
-            self.duration = duration;
-        } else {
-            self.duration = duration;
-        }
+        self.duration = duration;
+        self
+    }
+
+    /// Play only the part of the source between in and out points
+    pub fn trim(mut self, source_in: Duration, source_out: Duration) -> Self {
+        self.source_in = source_in;
+        self.source_out = Some(source_out);
+        self.duration = source_out.saturating_sub(source_in);
+        self
+    }
+
+    /// Start playing the source from position until its end
+    pub fn trim_start(mut self, source_in: Duration) -> Self {
+        self.source_in = source_in;
+        self.source_out = None;
+        self.duration = self.data.duration().saturating_sub(source_in);
+        self
+    }
+
+    /// What to do when the layer is longer than its (trimmed) source
+    pub fn end_behavior(mut self, end: EndBehavior) -> Self {
+        self.end = end;
+        self
+    }
+
+    /// Map layer time to source time (relative to the in point), for reverse, speed ramps & freeze frames.
+    /// Audio follows the curve, but is muted where it's nearly frozen.
+    pub fn time_remap(mut self, remap: Keyframes<Duration>) -> Self {
+        self.remap = Some(remap);
         self
     }
 
+    /// Length of the trimmed source
+    fn clip_duration(&self) -> Duration {
+        self.source_out.unwrap_or(self.data.duration()).saturating_sub(self.source_in)
+    }
+
+    /// Length on the timeline, the (trimmed) duration played at speed
+    fn timeline_duration(&self) -> Duration {
+        match self.speed.is_finite() && self.speed > 0.0 {
+            true => self.duration.div_f64(self.speed as f64),
+            false => self.duration
+        }
+    }
+
     /// Change the speed of this video
     pub fn speed(mut self, speed: f32) -> Self {
         self.speed = speed;
         self
     }
 
+    /// Set whether the audio changes pitch with speed. Time remapping always does.
+    pub fn speed_mode(mut self, mode: SpeedMode) -> Self {
+        self.speed_mode = mode;
+        self
+    }
+
+    /// Set role of the audio in sidechain ducking
+    pub fn sidechain(mut self, sidechain: Sidechain) -> Self {
+        self.sidechain = sidechain;
+        self
+    }
+
+    /// Set opacity of this layer (0 -> 1)
+    pub fn opacity(mut self, opacity: f32) -> Self {
+        self.opacity = opacity;
+        self
+    }
+
+    /// Limit the layer to mask (white = visible), stretched to the size of the frame.
+    /// For adjustment layers the frame is the whole output.
+    pub fn mask(mut self, mask: &DynamicImage) -> Self {
+        self.mask = Some(mask.to_luma8());
+        self
+    }
+
+    /// Set how this layer is blended with the layers below
+    pub fn blend(mut self, blend: BlendMode) -> Self {
+        self.blend = blend;
+        self
+    }
+
     /// Generate image from frame
-    pub fn frame(&self, offset: Duration, base: &mut DynamicImage, meta: &EditorMeta) -> Result<(), Error> {
-        let duration = Duration::from_secs_f32(self.duration.as_secs_f32() * self.speed);
-        if offset < self.offset || offset > (duration + self.offset) {
+    pub fn frame(&self, time: Timestamp, base: &mut DynamicImage, meta: &EditorMeta) -> Result<(), Error> {
+        self.render(time, base, meta, false)
+    }
+
+    /// Generate image from frame, hold = keep showing the last frame after the end
+    fn render(&self, time: Timestamp, base: &mut DynamicImage, meta: &EditorMeta, hold: bool) -> Result<(), Error> {
+        let duration = self.timeline_duration();
+        let end = self.offset + duration;
+        let time = match hold && time > end {
+            true => (end - Timestamp::from_frame(1, meta.fps)).max(self.offset),
+            false => time
+        };
+        if time < self.offset || time > end {
             return Ok(())
         }
-        let pos = Duration::from_secs_f32((offset - self.offset).as_secs_f32() * self.speed);
-        if let Ok(Some(mut frame)) = self.data.frame(pos) {
+        // Effects (& keyframes) run on timeline time like audio, the source on time scaled by speed
+        let layer_time = (time - self.offset).to_duration();
+        let pos = layer_time.mul_f64(self.speed as f64);
+        let source_pos = match source_position(self.remap.as_ref(), self.end, self.clip_duration(), pos) {
+            Some(source_pos) => source_pos,
+            None => return Ok(())
+        };
+        let frame = match self.adjustment {
+            true => Some(base.clone()),
+            false => self.data.frame(self.source_in + source_pos)?
+        };
+        if let Some(mut frame) = frame {
             // Effects
             let mut transform = self.transform;
             for effect in &self.effects {
-                frame = effect.apply_video_effect(frame, pos, duration, &mut transform,meta);
+                frame = effect.apply_video_effect(frame, layer_time, duration, &mut transform,meta);
+            }
+            if let Some(mask) = &self.mask {
+                frame = apply_mask(frame, mask);
             }
             // Merge
             let (x, y) = transform.calculate(meta.width, meta.height);
-            overlay(base, &frame, x, y);
+            composite(base, &frame, x, y, self.opacity, self.blend);
+        }
+        Ok(())
+    }
+
+    /// Get the audio in uniform format, trimmed, remapped & with effects
+    fn audio(&self, sample_rate: u32, channels: u16, cancel: &CancellationToken) -> Result<Option<AudioData>, Error> {
+        let audio = match self.data.audio()? {
+            Some(audio) => audio.trim(self.source_in, self.clip_duration()),
+            None => return Ok(None)
+        };
+        let audio = match &self.remap {
+            Some(remap) => {
+                let (remap, end, clip, speed) = (remap.clone(), self.end, self.clip_duration(), self.speed as f64);
+                audio.remap(self.timeline_duration(), move |t| source_position(Some(&remap), end, clip, t.mul_f64(speed)))
+            },
+            None => {
+                let audio = audio.end_behavior(self.end, self.clip_duration(), self.duration);
+                match self.speed_mode {
+                    SpeedMode::Resample => audio.speed(self.speed),
+                    SpeedMode::PreservePitch => audio.time_stretch(self.speed, cancel)?
+                }
+            }
+        };
+        // Time-aware effects (fades...) are placed on the same timeline length as the video
+        let mut audio = audio.uniform(sample_rate, channels);
+        let context = AudioContext { offset: self.offset, duration: self.timeline_duration(), sample_rate, channels };
+        for effect in &self.effects {
+            audio = effect.apply_audio_effect(audio, &context);
         }
-        return Ok(())
+        Ok(Some(audio))
     }
 }
 
+/// Position in clip at layer position, None = hidden
+fn source_position(remap: Option<&Keyframes<Duration>>, end: EndBehavior, clip: Duration, pos: Duration) -> Option<Duration> {
+    let pos = remap.and_then(|r| r.value(pos)).unwrap_or(pos);
+    end.map(pos, clip)
+}
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+
+/// Behavior of layer after its source ends
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum EndBehavior {
+    /// Disappear, audio stops
+    Hide,
+    /// Keep showing the last frame, audio stops
+    #[default]
+    Hold,
+    /// Start again from the beginning
+    Loop,
+    /// Play backwards, then forwards again...
+    PingPong,
+}
+
+impl EndBehavior {
+    /// Map position in layer to position in clip of length, None = hidden
+    pub(crate) fn map(&self, pos: Duration, clip: Duration) -> Option<Duration> {
+        if pos < clip || clip.is_zero() {
+            return Some(pos);
+        }
+        // Last moment inside the clip
+        let last = clip - Duration::from_nanos(1);
+        let clip_nanos = clip.as_nanos();
+        match self {
+            EndBehavior::Hide => None,
+            EndBehavior::Hold => Some(last),
+            EndBehavior::Loop => Some(Duration::from_nanos((pos.as_nanos() % clip_nanos) as u64)),
+            EndBehavior::PingPong => {
+                let t = pos.as_nanos() % (clip_nanos * 2);
+                match t < clip_nanos {
+                    true => Some(Duration::from_nanos(t as u64)),
+                    false => Some(last - Duration::from_nanos((t - clip_nanos) as u64))
+                }
+            }
+        }
+    }
+}
+
+/// Role of layer's audio in sidechain ducking, see `Renderer::ducking`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Sidechain {
+    #[default]
+    None,
+    /// Controls the ducking, e.g. voice
+    Key,
+    /// Gets quieter when the key is loud, e.g. music
+    Ducked,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum Transform {
     /// Pixels
     Px(i64, i64),
@@ -148,12 +618,24 @@ impl Transform {
 }
 
 pub trait EditorEffect {
-    /// Apply video effect and return the frame
+    /// Apply video effect and return the frame, offset is the time since the start of the layer on the timeline
     fn apply_video_effect(&self, frame: DynamicImage, offset: Duration, duration: Duration, transform: &mut Transform, meta: &EditorMeta) -> DynamicImage;
-    /// Apply audio effect and return mutated stream
-    fn apply_audio_effect(&self, audio: AudioData) -> AudioData;
+    /// Apply audio effect and return mutated stream, the stream starts at the start of the layer
+    fn apply_audio_effect(&self, audio: AudioData, context: &AudioContext) -> AudioData;
+}
+
+/// Timing & format of layer's audio, passed to audio effects
+#[derive(Debug, Clone, Copy)]
+pub struct AudioContext {
+    /// Start of the layer on the timeline
+    pub offset: Timestamp,
+    /// Length of the layer on the timeline
+    pub duration: Duration,
+    pub sample_rate: u32,
+    pub channels: u16,
 }
 
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Effect {
     /// Resize to base size, force to ignore aspect ratio
     ScaleToBase { force: bool }, 
@@ -164,7 +646,41 @@ pub enum Effect {
     /// Andle in radians, uncropped = slow
     RotateOverTime { a0: f32, a1: f32, uncropped: bool },
     MovePx { x: i64, y: i64 },
+    /// Multiply the layer's alpha, 0 -> 1
+    Opacity { opacity: f32 },
+    OpacityOverTime { o0: f32, o1: f32 },
+    /// Keyframed scale multiplier (x, y)
+    ScaleKeyframes { scale: Keyframes<(f32, f32)> },
+    /// Keyframed angle in radians, uncropped = slow
+    RotateKeyframes { angle: Keyframes<f32>, uncropped: bool },
+    /// Keyframed offset in pixels from the layer's position
+    MoveKeyframes { position: Keyframes<(f32, f32)> },
+    OpacityKeyframes { opacity: Keyframes<f32> },
+    /// Green screen, tolerance & softness are chroma distances (0 -> 1), spill (0 -> 1) removes color fringes
+    ChromaKey { color: [u8; 3], tolerance: f32, softness: f32, spill: f32 },
+    /// Brightness (-255 -> 255), contrast in percent, saturation multiplier (1 = unchanged), hue rotation in degrees
+    ColorAdjust { brightness: i32, contrast: f32, saturation: f32, hue: i32 },
     AudioGain { gain: f32 },
+    /// Keyframed gain multiplier, relative to the start of the layer
+    AudioGainKeyframes { gain: Keyframes<f32> },
+    /// Fade from silence at the start of the layer
+    FadeIn { duration: Duration, #[serde(default)] easing: Easing },
+    /// Fade to silence at the end of the layer
+    FadeOut { duration: Duration, #[serde(default)] easing: Easing },
+    /// Boost frequencies below frequency (Hz) by gain (dB), negative gain cuts
+    BassBoost { gain: Keyframes<f32>, frequency: Keyframes<f32> },
+    /// Cut frequencies above cutoff (Hz), "muffled from the next room"
+    LowPass { cutoff: Keyframes<f32> },
+    /// Cut frequencies below cutoff (Hz), telephone / radio
+    HighPass { cutoff: Keyframes<f32> },
+    /// Repeats after delay, feedback (0 -> 1) = level of each next repeat, mix = level of the repeats
+    Echo { delay: Duration, feedback: Keyframes<f32>, mix: Keyframes<f32> },
+    /// Room size, damping of high frequencies & wet/dry mix, all 0 -> 1
+    Reverb { room_size: Keyframes<f32>, damping: Keyframes<f32>, mix: Keyframes<f32> },
+    /// Bits per sample (1 -> 24), downsample = keep every nth sample
+    Bitcrush { bits: Keyframes<f32>, downsample: Keyframes<f32> },
+    /// Overdrive in dB, wet/dry mix 0 -> 1
+    Distortion { drive: Keyframes<f32>, mix: Keyframes<f32> },
 }
 
 impl EditorEffect for Effect {
@@ -218,18 +734,83 @@ impl EditorEffect for Effect {
                 *transform = Transform::px(x, y);
                 frame
             },
+            // Fade the frame
+            Effect::Opacity { opacity } => multiply_alpha(frame, *opacity),
+            // Fade the frame over time
+            Effect::OpacityOverTime { o0, o1 } => {
+                let t = offset.as_secs_f32() / duration.as_secs_f32();
+                multiply_alpha(frame, o0.lerp(*o1, t))
+            },
+            // Keyframed scale
+            Effect::ScaleKeyframes { scale } => {
+                let (x, y) = scale.value(offset).unwrap_or((1.0, 1.0));
+                let (w, h) = (frame.width() as f32 * x, frame.height() as f32 * y);
+                frame.resize_exact(w as u32, h as u32, FilterType::Nearest)
+            },
+            // Keyframed rotation
+            Effect::RotateKeyframes { angle, uncropped } => {
+                let a = angle.value(offset).unwrap_or(0.0);
+                match *uncropped {
+                    true => rotate_uncropped(&frame, a),
+                    false => rotate_about_center(&frame.to_rgba8(), a, Interpolation::Nearest, Rgba([0, 0, 0, 0])).into()
+                }
+            },
+            // Keyframed position
+            Effect::MoveKeyframes { position: keyframes } => {
+                let position = transform.calculate(meta.width, meta.height);
+                let (x, y) = keyframes.value(offset).unwrap_or((0.0, 0.0));
+                *transform = Transform::px(position.0 + x as i64, position.1 + y as i64);
+                frame
+            },
+            // Keyframed opacity
+            Effect::OpacityKeyframes { opacity } => multiply_alpha(frame, opacity.value(offset).unwrap_or(1.0)),
+            // Key out color
+            Effect::ChromaKey { color, tolerance, softness, spill } => chroma_key(frame, *color, *tolerance, *softness, *spill),
+            // Color correction
+            Effect::ColorAdjust { brightness, contrast, saturation, hue } => color_adjust(frame, *brightness, *contrast, *saturation, *hue),
             // Audio effects
-            Effect::AudioGain { .. } => frame
+            Effect::AudioGain { .. } | Effect::AudioGainKeyframes { .. } | Effect::FadeIn { .. } | Effect::FadeOut { .. } |
+            Effect::BassBoost { .. } | Effect::LowPass { .. } | Effect::HighPass { .. } | Effect::Echo { .. } |
+            Effect::Reverb { .. } | Effect::Bitcrush { .. } | Effect::Distortion { .. } => frame
         }
     }
 
     /// Apply audio effect on source
-    fn apply_audio_effect(&self, audio: AudioData) -> AudioData {
+    fn apply_audio_effect(&self, audio: AudioData, context: &AudioContext) -> AudioData {
         match self {
             // Add gain to the audio
             Effect::AudioGain { gain } => {
                 AudioData::new(audio.source.amplify(*gain))
             },
+            // Keyframed gain
+            Effect::AudioGainKeyframes { gain } => {
+                let gain = gain.clone();
+                audio.envelope(move |t| gain.value(t).unwrap_or(1.0))
+            },
+            // Fades
+            Effect::FadeIn { duration, easing } => {
+                let (duration, easing) = (*duration, *easing);
+                audio.envelope(move |t| easing.ease(fade_progress(t, Duration::ZERO, duration)))
+            },
+            Effect::FadeOut { duration, easing } => {
+                let (duration, easing) = (*duration, *easing);
+                let start = context.duration.saturating_sub(duration);
+                audio.envelope(move |t| 1.0 - easing.ease(fade_progress(t, start, duration)))
+            },
+            // DSP
+            Effect::BassBoost { gain, frequency } => {
+                audio.process(Filter::low_shelf(frequency.clone(), gain.clone(), context.sample_rate, context.channels))
+            },
+            Effect::LowPass { cutoff } => audio.process(Filter::low_pass(cutoff.clone(), context.sample_rate, context.channels)),
+            Effect::HighPass { cutoff } => audio.process(Filter::high_pass(cutoff.clone(), context.sample_rate, context.channels)),
+            Effect::Echo { delay, feedback, mix } => {
+                audio.process(Echo::new(*delay, feedback.clone(), mix.clone(), context.sample_rate, context.channels))
+            },
+            Effect::Reverb { room_size, damping, mix } => {
+                audio.process(Reverb::new(room_size.clone(), damping.clone(), mix.clone(), context.sample_rate, context.channels))
+            },
+            Effect::Bitcrush { bits, downsample } => audio.process(Bitcrush::new(bits.clone(), downsample.clone(), context.channels)),
+            Effect::Distortion { drive, mix } => audio.process(Distortion::new(drive.clone(), mix.clone())),
 
             // Video effects
             _ => audio
@@ -237,6 +818,21 @@ impl EditorEffect for Effect {
     }
 }
 
+/// Progress 0 -> 1 of fade starting at start
+fn fade_progress(t: Duration, start: Duration, duration: Duration) -> f32 {
+    if duration.is_zero() {
+        return if t >= start { 1.0 } else { 0.0 };
+    }
+    (t.saturating_sub(start).as_secs_f32() / duration.as_secs_f32()).min(1.0)
+}
+
+/// Message of caught panic
+fn panic_message(panic: &Box<dyn Any + Send>) -> &str {
+    panic.downcast_ref::<&str>().copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic")
+}
+
 /// Data of layer
 pub trait LayerData {
     /// Get duration of this layer
@@ -263,6 +859,43 @@ impl AudioData {
         AudioData::new(UniformSourceIterator::new(self.source, channels, sample_rate))
     }
 
+    /// Multiply samples by gain function of time since the start
+    pub fn envelope(self, gain: impl Fn(Duration) -> f32 + Send + Sync + 'static) -> Self {
+        AudioData::new(Envelope { source: self.source, gain, sample: 0 })
+    }
+
+    /// Run processor over the samples, see `crate::dsp`
+    pub fn process(self, processor: impl AudioProcessor + Send + Sync + 'static) -> Self {
+        AudioData::new(Processed::new(self.source, processor))
+    }
+
+    /// Skip to start and play only length of the source
+    fn trim(self, start: Duration, length: Duration) -> Self {
+        let source = self.source.skip_duration(start).take_duration(length);
+        AudioData::new(source)
+    }
+
+    /// Extend or cut clip to length of the layer, has to be called after trim
+    fn end_behavior(self, end: EndBehavior, clip: Duration, length: Duration) -> Self {
+        if length <= clip || matches!(end, EndBehavior::Hide | EndBehavior::Hold) {
+            return AudioData::new(self.source.take_duration(length));
+        }
+        let channels = self.source.channels();
+        let sample_rate = self.source.sample_rate();
+        let samples = self.source.collect::<Vec<_>>();
+        let total = Timestamp::from(length).ticks_in(Rational::new(1, sample_rate as i64)) as usize * channels as usize;
+        AudioData::new(Repeat { samples, channels, sample_rate, ping_pong: end == EndBehavior::PingPong, index: 0, total })
+    }
+
+    /// Play the clip along mapping from output time to clip time, has to be called after trim
+    fn remap(self, length: Duration, map: impl Fn(Duration) -> Option<Duration> + Send + Sync + 'static) -> Self {
+        let channels = self.source.channels();
+        let sample_rate = self.source.sample_rate();
+        let samples = self.source.collect::<Vec<_>>();
+        let total = Timestamp::from(length).ticks_in(Rational::new(1, sample_rate as i64)) as usize;
+        AudioData::new(Remap { samples, channels, sample_rate, map, frame: 0, total, current: vec![], channel: 0 })
+    }
+
     /// Change speed of this audio
     /// WARNING: Call before uniform
     fn speed(self, speed: f32) -> Self {
@@ -272,17 +905,244 @@ impl AudioData {
             AudioData::new(self.source.speed(speed))
         }
     }
+
+    /// Change speed of this audio without changing the pitch
+    /// WARNING: Call before uniform
+    fn time_stretch(self, speed: f32, cancel: &CancellationToken) -> Result<Self, Cancelled> {
+        if speed == 1.0 {
+            return Ok(self);
+        }
+        let channels = self.source.channels();
+        let sample_rate = self.source.sample_rate();
+        let samples = self.source.collect::<Vec<_>>();
+        let samples = time_stretch(&samples, channels, sample_rate, speed, cancel)?;
+        Ok(AudioData::new(SamplesBuffer::new(channels, sample_rate, samples)))
+    }
+}
+
+/// Buffered clip played repeatedly
+struct Repeat {
+    samples: Vec<f32>,
+    channels: u16,
+    sample_rate: u32,
+    ping_pong: bool,
+    /// Current sample in output
+    index: usize,
+    total: usize,
+}
+
+impl Iterator for Repeat {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.index >= self.total || self.samples.len() < self.channels as usize {
+            return None;
+        }
+        let channels = self.channels as usize;
+        let len = self.samples.len();
+        let index = match self.ping_pong {
+            false => self.index % len,
+            true => {
+                // Reverse whole frames, so channels stay in place
+                let frames = len / channels;
+                let (frame, channel) = (self.index / channels % (frames * 2), self.index % channels);
+                match frame < frames {
+                    true => frame * channels + channel,
+                    false => (frames * 2 - 1 - frame) * channels + channel
+                }
+            }
+        };
+        self.index += 1;
+        self.samples.get(index).copied()
+    }
+}
+
+impl Source for Repeat {
+    fn current_frame_len(&self) -> Option<usize> {
+        Some(self.total - self.index)
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        Some(Timestamp::new((self.total / self.channels as usize) as i64, Rational::new(1, self.sample_rate as i64)).to_duration())
+    }
+}
+
+/// Buffered clip played along a time mapping
+struct Remap<F> {
+    samples: Vec<f32>,
+    channels: u16,
+    sample_rate: u32,
+    map: F,
+    /// Current output frame (sample of every channel)
+    frame: usize,
+    total: usize,
+    current: Vec<f32>,
+    channel: usize,
+}
+
+impl<F> Remap<F> {
+    /// Slower than this is a freeze frame, muted
+    const MIN_SPEED: f64 = 0.1;
+}
+
+impl<F: Fn(Duration) -> Option<Duration>> Remap<F> {
+    /// Position in source frames at output frame
+    fn position(&self, frame: usize) -> Option<f64> {
+        let t = Timestamp::from_samples(frame, self.sample_rate).to_duration();
+        (self.map)(t).map(|p| p.as_secs_f64() * self.sample_rate as f64)
+    }
+
+    /// Interpolated samples of all channels at output frame
+    fn read_frame(&self, frame: usize) -> Vec<f32> {
+        let channels = self.channels as usize;
+        let frames = self.samples.len() / channels;
+        let (pos, next) = (self.position(frame), self.position(frame + 1));
+        match (pos, next) {
+            (Some(pos), Some(next)) if (next - pos).abs() >= Self::MIN_SPEED && (pos as usize) < frames => {
+                let i = pos as usize;
+                let j = (i + 1).min(frames - 1);
+                let t = pos.fract() as f32;
+                (0..channels).map(|c| self.samples[i * channels + c].lerp(self.samples[j * channels + c], t)).collect()
+            },
+            _ => vec![0.0; channels]
+        }
+    }
+}
+
+impl<F: Fn(Duration) -> Option<Duration>> Iterator for Remap<F> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.channel == 0 {
+            if self.frame >= self.total || self.channels == 0 {
+                return None;
+            }
+            self.current = self.read_frame(self.frame);
+            self.frame += 1;
+        }
+        let sample = self.current[self.channel];
+        self.channel = (self.channel + 1) % self.channels as usize;
+        Some(sample)
+    }
 }
 
+impl<F: Fn(Duration) -> Option<Duration>> Source for Remap<F> {
+    fn current_frame_len(&self) -> Option<usize> {
+        let channels = self.channels as usize;
+        Some((self.total - self.frame) * channels + (channels - self.channel) % channels.max(1))
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        Some(Timestamp::from_samples(self.total, self.sample_rate).to_duration())
+    }
+}
+
+/// Source wrapper applying time dependant gain
+struct Envelope<F> {
+    source: Box<dyn Source<Item = f32> + Send + Sync>,
+    gain: F,
+    sample: u64,
+}
+
+impl<F: Fn(Duration) -> f32> Iterator for Envelope<F> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let value = self.source.next()?;
+        let rate = self.source.sample_rate() as i64 * self.source.channels() as i64;
+        let t = Timestamp::new(self.sample as i64, Rational::new(1, rate)).to_duration();
+        self.sample += 1;
+        Some(value * (self.gain)(t))
+    }
+}
+
+impl<F: Fn(Duration) -> f32> Source for Envelope<F> {
+    fn current_frame_len(&self) -> Option<usize> {
+        self.source.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.source.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.source.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.source.total_duration()
+    }
+}
+
+/// Receives rendered frames in order
+pub trait FrameSink {
+    /// Consume the next frame
+    fn write_frame(&mut self, index: usize, frame: DynamicImage) -> Result<(), Error>;
+}
 
 pub struct Renderer {
     editor: Editor, 
+    hooks: RenderHooks,
+    error_mode: ErrorMode,
+    master: MasterBus,
+    ducking: Ducking,
 }
 
 impl Renderer {
     /// Create new renderer instance
     pub fn new(editor: Editor) -> Renderer {
-        Renderer { editor }
+        Renderer { editor, hooks: RenderHooks::default(), error_mode: ErrorMode::Strict, master: MasterBus::default(), ducking: Ducking::default() }
+    }
+
+    /// Set processing of the final audio mix
+    pub fn master(mut self, master: MasterBus) -> Self {
+        self.master = master;
+        self
+    }
+
+    /// Set how layers with `Sidechain::Ducked` react to `Sidechain::Key` layers
+    pub fn ducking(mut self, ducking: Ducking) -> Self {
+        self.ducking = ducking;
+        self
+    }
+
+    /// Set how are failing frames handled
+    pub fn error_mode(mut self, error_mode: ErrorMode) -> Self {
+        self.error_mode = error_mode;
+        self
+    }
+
+    /// Get called with progress of every render stage
+    pub fn on_progress(mut self, callback: impl Fn(Progress) + Send + Sync + 'static) -> Self {
+        self.hooks = self.hooks.on_progress(callback);
+        self
+    }
+
+    /// Stop rendering once the token is cancelled, render methods return `Cancelled` error
+    pub fn cancellation(mut self, token: CancellationToken) -> Self {
+        self.hooks = self.hooks.cancellation(token);
+        self
+    }
+
+    /// Get the progress & cancellation hooks
+    pub fn hooks(&self) -> &RenderHooks {
+        &self.hooks
     }
 
     /// Frame count of final output
@@ -290,88 +1150,37 @@ impl Renderer {
         self.editor.meta.frames()
     }
 
-    /// Render single frame
+    /// Render single frame, fails on the first layer error
     pub fn render_frame(&self, frame_index: usize) -> Result<Option<DynamicImage>, Error> {
-        if frame_index >= self.editor.meta.frames() {
-            return Ok(None);
-        }
+        Ok(self.compose_frame(frame_index, None)?)
+    }
 
-        // Create base frame
-        let base = RgbImage::from_raw(self.editor.meta.width, self.editor.meta.height, vec![0u8; self.editor.meta.width as usize * self.editor.meta.height as usize * 3]).unwrap();
-        let mut base = DynamicImage::from(base);
-        for layer in &self.editor.layers {
-            layer.frame(Duration::from_secs_f32(frame_index as f32 / self.editor.meta.fps), &mut base, &self.editor.meta)?;
+    /// Render single frame according to error mode, returns errors of skipped layers
+    fn render_frame_checked(&self, frame_index: usize) -> Result<(Option<DynamicImage>, Vec<LayerError>), LayerError> {
+        match self.error_mode {
+            ErrorMode::Strict => Ok((self.compose_frame(frame_index, None)?, vec![])),
+            ErrorMode::Lenient => {
+                let mut errors = vec![];
+                let frame = self.compose_frame(frame_index, Some(&mut errors))?;
+                Ok((frame, errors))
+            }
         }
-        Ok(Some(base))
+    }
+
+    /// Compose all layers, if errors are provided failing layers get skipped
+    fn compose_frame(&self, frame_index: usize, errors: Option<&mut Vec<LayerError>>) -> Result<Option<DynamicImage>, LayerError> {
+        self.editor.compose_frame(frame_index, errors)
     }
 
     /// Render audio
     pub fn render_audio(&self, sample_rate: u32, channels: u16) -> Result<Vec<f32>, Error> {
-        // Get sources
-        let mut output = vec![];
-        let duration = self.editor.meta.duration;
-        let mut sample: usize = 0;
-        let mut queue = self.editor.layers.iter().filter(|l| matches!(l.data.audio(), Ok(Some(_)))).collect::<Vec<_>>();
-        let mut sources = vec![];
-        // Iter over samples
-        loop {
-            // EOF
-            let pos = Duration::from_secs_f32(sample as f32 / sample_rate as f32);
-            if pos > duration  {
-                break;
-            }
-
-            // Find audio source
-            if !queue.is_empty() {
-                let mut i = 0;
-                loop {
-                    if &queue[i].offset <= &pos {
-                        // Make sure they're the same format
-                        let layer = queue.remove(i);
-                        let mut src = layer.data.audio()?.unwrap().speed(layer.speed).uniform(sample_rate, channels);
-                        // Apply effects
-                        for effect in &layer.effects {
-                            src = effect.apply_audio_effect(src);
-                        }
-                        sources.push(src.source);
-                    } else {
-                        i += 1;
-                    }
-                    if i == queue.len() {
-                        break;
-                    }
-                }
-            }
-
-            // Merge audio sources
-            for _ in 0..channels {
-                let mut sample = vec![];
-                let mut new_sources = vec![];
-                for mut source in sources {
-                    match source.next() {
-                        Some(s) => {
-                            sample.push(s);
-                            new_sources.push(source);
-                        },
-                        None => continue
-                    }
-                }
-                // Average out the source
-                sources = new_sources;
-                if sample.is_empty() {
-                    output.push(0.0)
-                } else {
-                    output.push(sample.iter().sum::<f32>()) // / sample.len() as f32                    
-                }
-            }
-            sample += 1;
-        }
-
-        return Ok(output)
+        let mut output = self.editor.mix_audio(sample_rate, channels, &self.ducking, &self.hooks)?;
+        self.master.process(&mut output, sample_rate, channels);
+        Ok(output)
     }
 
-    /// Render full video with multiple threads
-    pub fn render_full_multithreaded(self: Arc<Self>, output: impl AsRef<Path>, threads: usize) -> Result<Arc<Self>, Error> {
+    /// Render full video with multiple threads into numbered PNGs
+    pub fn render_full_multithreaded(self: &Arc<Self>, output: impl AsRef<Path>, threads: usize) -> Result<RenderReport, Error> {
         std::fs::create_dir_all(&output)?;
         let frame_count = self.frame_count();
         let (tx, rx) = unbounded();
@@ -384,25 +1193,159 @@ impl Renderer {
             let output = output.clone();
             pool.execute( move || {
                 // Render the frame
-                let render_frame = || -> Result<(), Error> {
-                    let frame = renderer.render_frame(i)?.ok_or(anyhow!("Missing frame"))?;
+                let render_frame = || -> Result<Vec<LayerError>, Error> {
+                    renderer.hooks.cancel.check()?;
+                    let (frame, errors) = renderer.render_frame_checked(i)?;
+                    let frame = frame.ok_or(anyhow!("Missing frame"))?;
                     frame.save(output.join(format!("{:06}.png", i+1)))?;
-                    Ok(())
+                    Ok(errors)
                 };
-                tx.send(render_frame()).ok();
-                
+                // Panicking worker would never send its frame
+                let result = catch_unwind(AssertUnwindSafe(render_frame))
+                    .unwrap_or_else(|panic| Err(anyhow!("Panicked: {}", panic_message(&panic))));
+                tx.send((i, result)).ok();
             });
         }
         // Count the frames
         std::mem::drop(tx);
-        let mut i = 0;
-        for _ in rx {
-            i += 1;
-            if i % 50 == 0 {
-                debug!("Done: {i} / {frame_count}");
+        let tracker = self.hooks.tracker(Stage::Video, frame_count);
+        let mut report = RenderReport::default();
+        for (i, result) in rx {
+            self.hooks.cancel.check()?;
+            report.add(i, result);
+            tracker.report(report.frames);
+            if report.frames % 50 == 0 {
+                debug!("Done: {} / {frame_count}", report.frames);
             }
         }
-        Ok(self)
+        if report.frames != frame_count {
+            return Err(anyhow!("Only {} of {frame_count} frames were rendered", report.frames));
+        }
+        report.finish(self.error_mode)
+    }
+
+    /// Render frames with multiple threads and pass them to sink in order.
+    /// At most `threads * 2` frames are kept in memory at once.
+    /// In lenient mode frames which failed completely are replaced with black ones,
+    /// in strict mode nothing is written after the first failure, but all frames are still rendered to report every failure.
+    pub fn render_to_sink(self: &Arc<Self>, sink: &mut dyn FrameSink, threads: usize) -> Result<RenderReport, Error> {
+        let frame_count = self.frame_count();
+        let (tx, rx) = unbounded();
+        let pool = ThreadPool::new(threads);
+        let submit = |i: usize| {
+            let tx = tx.clone();
+            let renderer = self.clone();
+            pool.execute(move || {
+                // Panicking worker would never send its frame
+                let frame = catch_unwind(AssertUnwindSafe(|| match renderer.hooks.cancel.check() {
+                    Ok(_) => renderer.render_frame_checked(i).map_err(Error::from),
+                    Err(e) => Err(e.into())
+                })).unwrap_or_else(|panic| Err(anyhow!("Panicked: {}", panic_message(&panic))));
+                tx.send((i, frame)).ok();
+            });
+        };
+        // Start threadpool
+        let max_pending = threads.max(1) * 2;
+        let mut next_submit = frame_count.min(max_pending);
+        (0..next_submit).for_each(submit);
+
+        // Write in order
+        let tracker = self.hooks.tracker(Stage::Video, frame_count);
+        let mut report = RenderReport::default();
+        let mut pending = BTreeMap::new();
+        let mut next_write = 0;
+        // Strict render keeps going to collect all failures, but the output is useless
+        let mut failed = false;
+        while next_write < frame_count {
+            let (i, frame) = rx.recv()?;
+            self.hooks.cancel.check()?;
+            pending.insert(i, frame);
+            while let Some(result) = pending.remove(&next_write) {
+                let frame = match result {
+                    Ok((frame, errors)) => {
+                        report.add(next_write, Ok(errors));
+                        frame.ok_or(anyhow!("Missing frame"))?
+                    },
+                    Err(error) => {
+                        report.add(next_write, Err(error));
+                        failed |= self.error_mode == ErrorMode::Strict;
+                        DynamicImage::new_rgb8(self.editor.meta.width, self.editor.meta.height)
+                    }
+                };
+                if !failed {
+                    sink.write_frame(next_write, frame)?;
+                }
+                next_write += 1;
+                tracker.report(next_write);
+                if next_submit < frame_count {
+                    submit(next_submit);
+                    next_submit += 1;
+                }
+                if next_write % 50 == 0 {
+                    debug!("Done: {next_write} / {frame_count}");
+                }
+            }
+        }
+        report.finish(self.error_mode)
+    }
+
+    /// Render animated GIF, returns the report & final size in bytes
+    pub fn render_gif(self: &Arc<Self>, output: impl AsRef<Path>, options: GifOptions, threads: usize) -> Result<(RenderReport, u64), Error> {
+        let meta = &self.editor.meta;
+        let mut sink = GifSink::new(meta.width, meta.height, meta.fps, options)?;
+        let report = self.render_to_sink(&mut sink, threads)?;
+        let tracker = self.hooks.tracker(Stage::Encode, 1);
+        let size = sink.finish(output)?;
+        tracker.report(1);
+        Ok((report, size))
+    }
+
+    /// Render animated WebP through ffmpeg (needs libwebp), honours loops & max size of the options.
+    /// Returns the report & final size in bytes.
+    pub fn render_webp(self: &Arc<Self>, ffmpeg: &FFmpeg, output: impl AsRef<Path>, options: GifOptions, threads: usize) -> Result<(RenderReport, u64), Error> {
+        let meta = &self.editor.meta;
+        let mut sink = WebpSink::new(meta.width, meta.height, meta.fps, options);
+        let report = self.render_to_sink(&mut sink, threads)?;
+        let tracker = self.hooks.tracker(Stage::Encode, 1);
+        let size = sink.finish(ffmpeg, output)?;
+        tracker.report(1);
+        Ok((report, size))
+    }
+
+    /// Render video with audio and encode it with ffmpeg in one go, without saving frames to disk.
+    /// If no args are given, defaults are picked based on the output extension (mp4 / webm / animated webp).
+    /// WebP uses a fixed preset here, see `render_webp` for loops & size limits.
+    pub fn render_encoded<A, O>(self: &Arc<Self>, ffmpeg: &FFmpeg, output: impl AsRef<Path>, threads: usize, sample_rate: u32, channels: u16, args: A) -> Result<RenderReport, Error>
+    where
+        A: IntoIterator<Item = O>,
+        O: AsRef<std::ffi::OsStr>
+    {
+        // Mixed audio goes through temporary file, stdin is used for video
+        let audio_path = std::env::temp_dir().join(format!("videoeditor-{}-{}.wav", std::process::id(), rand::random::<u32>()));
+        let audio = FFmpeg::supports_audio(output.as_ref());
+        if audio {
+            self.render_audio_wav(&audio_path, sample_rate, channels)?;
+        }
+
+        let render = || -> Result<RenderReport, Error> {
+            let meta = &self.editor.meta;
+            let mut encoder = ffmpeg.encoder(meta.width, meta.height, meta.fps, audio.then_some(audio_path.as_path()), &output, args)?;
+            let result = match self.render_to_sink(&mut encoder, threads) {
+                Ok(report) => encoder.finish().map(|_| report),
+                Err(e) => {
+                    std::mem::drop(encoder);
+                    Err(e)
+                }
+            };
+            // Don't leave a truncated video behind
+            if result.is_err() {
+                std::fs::remove_file(&output).ok();
+            }
+            result
+        };
+        let result = render();
+        std::fs::remove_file(&audio_path).ok();
+        result
     }
 
     /// Render audio to .wav
@@ -437,4 +1380,129 @@ fn rotate_uncropped(image: &DynamicImage, angle: f32) -> DynamicImage {
     // Rotate
     let output = rotate_about_center(&new_image, angle, Interpolation::Nearest, Rgba([0, 0, 0, 0u8]));
     output.into()
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// White frames and constant audio level, sampled at 1kHz
+    struct Constant {
+        seconds: u64,
+        level: f32,
+    }
+
+    impl LayerData for Constant {
+        fn duration(&self) -> Duration {
+            Duration::from_secs(self.seconds)
+        }
+
+        fn frame(&self, _offset: Duration) -> Result<Option<DynamicImage>, Error> {
+            Ok(Some(DynamicImage::from(RgbImage::from_pixel(2, 2, image::Rgb([255; 3])))))
+        }
+
+        fn audio(&self) -> Result<Option<AudioData>, Error> {
+            Ok(Some(AudioData::new(SamplesBuffer::new(1, 1000, vec![self.level; self.seconds as usize * 1000]))))
+        }
+    }
+
+    /// Panics on every other frame
+    struct Panicking;
+
+    impl LayerData for Panicking {
+        fn duration(&self) -> Duration {
+            Duration::from_secs(1)
+        }
+
+        fn frame(&self, offset: Duration) -> Result<Option<DynamicImage>, Error> {
+            match Timestamp::from(offset).frame(Rational::from(10)) % 2 {
+                0 => Ok(Some(DynamicImage::from(RgbImage::new(2, 2)))),
+                _ => panic!("Broken frame")
+            }
+        }
+
+        fn audio(&self) -> Result<Option<AudioData>, Error> {
+            Ok(None)
+        }
+    }
+
+    #[test]
+    fn panicking_frames_are_reported() {
+        let dir = std::env::temp_dir().join(format!("videoeditor-panic-{}", std::process::id()));
+        let editor = Editor::new(2, 2, Duration::from_secs(1), 10u32)
+            .layer(Layer::new(Box::new(Panicking), Duration::ZERO, Transform::ZERO));
+        let renderer = Arc::new(Renderer::new(editor).error_mode(ErrorMode::Lenient));
+        let report = renderer.render_full_multithreaded(&dir, 4).unwrap();
+        assert_eq!(report.frames, 10);
+        assert_eq!(report.failed.iter().map(|f| f.frame).collect::<Vec<_>>(), [1, 3, 5, 7, 9]);
+        assert!(report.failed[0].error.to_string().contains("Broken frame"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn ducking() {
+        // Music bed all the time, voice from 2s to 3s
+        let editor = Editor::new(2, 2, Duration::from_secs(6), 10u32)
+            .layer(Layer::new(Box::new(Constant { seconds: 6, level: 0.1 }), Duration::ZERO, Transform::ZERO).sidechain(Sidechain::Ducked))
+            .layer(Layer::new(Box::new(Constant { seconds: 1, level: 0.5 }), Duration::from_secs(2), Transform::ZERO).sidechain(Sidechain::Key));
+        let ducking = Ducking::default().threshold(-30.0).ratio(4.0).attack(Duration::from_millis(20)).release(Duration::from_millis(400));
+        let audio = editor.mix_audio(1000, 1, &ducking, &RenderHooks::default()).unwrap();
+        let level = |t: f32| audio[(t * 1000.0) as usize];
+
+        assert!((level(1.0) - 0.1).abs() < 1e-3);
+        // Voice is 24dB over the threshold, 4:1 = the bed is reduced by 18dB
+        let bed = level(2.5) - 0.5;
+        assert!((bed - 0.1 * 10f32.powf(-18.0 / 20.0)).abs() < 2e-3, "{bed}");
+        // Comes back gradually after the voice
+        assert!(level(3.2) < 0.05);
+        assert!(level(3.2) > bed);
+        assert!((level(5.0) - 0.1).abs() < 1e-3);
+    }
+
+    #[test]
+    fn fade_out_at_speed() {
+        let secs = Duration::from_secs_f32;
+        for (speed, mode) in [(2.0, SpeedMode::Resample), (0.5, SpeedMode::Resample), (2.0, SpeedMode::PreservePitch)] {
+            let layer = Layer::new(Box::new(Constant { seconds: 4, level: 0.5 }), Duration::ZERO, Transform::ZERO)
+                .speed(speed)
+                .speed_mode(mode)
+                .effect(Effect::FadeOut { duration: secs(1.0), easing: Easing::Linear });
+            let length = 4.0 / speed;
+            let audio = layer.audio(1000, 1, &CancellationToken::new()).unwrap().unwrap().source.collect::<Vec<_>>();
+            assert!((audio.len() as f32 - length * 1000.0).abs() < 50.0, "speed {speed}: {} samples", audio.len());
+            // Full level until the last second, then linearly down to silence at the end
+            let level = |t: f32| audio[(t * 1000.0) as usize];
+            assert!((level(length - 1.5) - 0.5).abs() < 0.02, "speed {speed}");
+            assert!((level(length - 0.5) - 0.25).abs() < 0.02, "speed {speed}");
+            assert!(level(length - 0.02) < 0.02, "speed {speed}");
+
+            // Video is visible for the same time as the audio plays
+            let meta = EditorMeta::new(2, 2, secs(length + 1.0), 10u32);
+            let visible = |t: f32| {
+                let mut base = DynamicImage::from(RgbImage::new(2, 2));
+                layer.frame(Timestamp::from(secs(t)), &mut base, &meta).unwrap();
+                base.to_rgb8().get_pixel(0, 0).0 != [0; 3]
+            };
+            assert!(visible(length - 0.1), "speed {speed}");
+            assert!(!visible(length + 0.1), "speed {speed}");
+        }
+    }
+
+    #[test]
+    fn end_behavior() {
+        let ms = Duration::from_millis;
+        let clip = ms(1000);
+        let last = clip - Duration::from_nanos(1);
+        for behavior in [EndBehavior::Hide, EndBehavior::Hold, EndBehavior::Loop, EndBehavior::PingPong] {
+            assert_eq!(behavior.map(ms(400), clip), Some(ms(400)));
+            assert_eq!(behavior.map(ms(400), Duration::ZERO), Some(ms(400)));
+        }
+        assert_eq!(EndBehavior::Hide.map(clip, clip), None);
+        assert_eq!(EndBehavior::Hold.map(ms(5000), clip), Some(last));
+        assert_eq!(EndBehavior::Loop.map(clip, clip), Some(Duration::ZERO));
+        assert_eq!(EndBehavior::Loop.map(ms(2300), clip), Some(ms(300)));
+        assert_eq!(EndBehavior::PingPong.map(clip, clip), Some(last));
+        assert_eq!(EndBehavior::PingPong.map(ms(1300), clip), Some(last - ms(300)));
+        assert_eq!(EndBehavior::PingPong.map(ms(2300), clip), Some(ms(300)));
+    }
+}
@@ -1,10 +1,17 @@
+use std::io::{BufRead, BufReader, Read, Write};
 use std::path::Path;
-use std::process::{Command, Child, Stdio};
-use std::ffi::OsStr;
+use std::process::{Command, Child, ChildStdin, ChildStdout, Stdio};
+use std::ffi::{OsStr, OsString};
 use std::time::Duration;
 use anyhow::Error;
+use image::{DynamicImage, RgbImage};
+use rodio::Source;
 
+use crate::editor::FrameSink;
+use crate::progress::{RenderHooks, Stage, Cancelled};
+use crate::probe::MediaProbe;
 use crate::source::VideoMeta;
+use crate::time::Rational;
 
 /// Wait for ffmpeg output
 fn wait_output(child: Child) -> Result<(), Error> {
@@ -60,23 +67,255 @@ impl FFmpeg {
         Ok(())
     }
 
+    /// ffmpeg convert command reporting progress (in ms of output) and supporting cancellation
+    pub fn convert_with_progress<A, O>(&self, path: impl AsRef<Path>, output: impl AsRef<Path>, args: A, hooks: &RenderHooks) -> Result<(), Error>
+    where
+        A: IntoIterator<Item = O>,
+        O: AsRef<OsStr>
+    {
+        let total = self.duration(&path).map(|d| d.as_millis() as usize).unwrap_or(0);
+        let mut child = self.ffmpeg(false, true)
+            .args(["-progress", "pipe:1", "-nostats"])
+            .arg("-i").arg(path.as_ref().as_os_str())
+            .args(args)
+            .arg(output.as_ref().as_os_str())
+            .spawn()?;
+        let stdout = child.stdout.take().ok_or(anyhow!("Missing ffmpeg stdout"))?;
+        let tracker = hooks.tracker(Stage::Encode, total);
+        // Parse key=value progress lines
+        for line in BufReader::new(stdout).lines() {
+            let line = line?;
+            if hooks.cancel.is_cancelled() {
+                child.kill().ok();
+                child.wait().ok();
+                return Err(Cancelled.into());
+            }
+            // Both are in microseconds
+            let time = line.strip_prefix("out_time_us=").or_else(|| line.strip_prefix("out_time_ms="));
+            if let Some(Ok(us)) = time.map(|t| t.trim().parse::<u64>()) {
+                tracker.report((us / 1000) as usize);
+            }
+        }
+        wait_output(child)?;
+        Ok(())
+    }
+
+    /// Get duration of media file
+    pub fn duration(&self, path: impl AsRef<Path>) -> Result<Duration, Error> {
+        self.probe(&path)?.duration().ok_or(anyhow!("Unknown duration: {:?}", path.as_ref()))
+    }
+
+    /// Use ffprobe to get info about all streams, format and chapters
+    pub fn probe(&self, path: impl AsRef<Path>) -> Result<MediaProbe, Error> {
+        let output = Command::new(&self.ffprobe)
+            .args(["-v", "error", "-show_format", "-show_streams", "-show_chapters", "-of", "json"])
+            .arg(path.as_ref().as_os_str())
+            .output()?;
+        if !output.status.success() {
+            return Err(anyhow!("ffprobe failed: {}", String::from_utf8_lossy(&output.stderr)));
+        }
+        MediaProbe::from_json(&String::from_utf8_lossy(&output.stdout))
+    }
+
+    /// Spawn encoder reading raw RGB frames from stdin, audio is an optional extra input.
+    /// If no args are given, defaults are picked based on the output extension.
+    pub fn encoder<A, O>(&self, width: u32, height: u32, fps: impl Into<Rational>, audio: Option<&Path>, output: impl AsRef<Path>, args: A) -> Result<Encoder, Error>
+    where
+        A: IntoIterator<Item = O>,
+        O: AsRef<OsStr>
+    {
+        let mut args = args.into_iter().map(|a| a.as_ref().to_owned()).collect::<Vec<OsString>>();
+        if args.is_empty() {
+            args = FFmpeg::default_encoder_args(output.as_ref()).into_iter().map(OsString::from).collect();
+        }
+        let mut command = self.ffmpeg(true, false);
+        command
+            .args(["-f", "rawvideo", "-pix_fmt", "rgb24"])
+            .arg("-s").arg(format!("{width}x{height}"))
+            .arg("-r").arg(fps.into().to_string())
+            .args(["-i", "pipe:0"]);
+        if let Some(audio) = audio {
+            command.arg("-i").arg(audio.as_os_str());
+        }
+        let mut child = command
+            .args(args)
+            .arg(output.as_ref().as_os_str())
+            .spawn()?;
+        Ok(Encoder { stdin: child.stdin.take(), child: Some(child), width, height })
+    }
+
+    /// Check if ffmpeg was built with encoder, e.g. `libwebp_anim`
+    pub fn has_encoder(&self, name: &str) -> Result<bool, Error> {
+        let output = Command::new(&self.ffmpeg).args(["-hide_banner", "-encoders"]).output()?;
+        Ok(String::from_utf8_lossy(&output.stdout).lines().any(|l| l.split_whitespace().nth(1) == Some(name)))
+    }
+
+    /// Encoding arguments for the output container
+    fn default_encoder_args(output: &Path) -> Vec<&'static str> {
+        match &FFmpeg::extension(output)[..] {
+            "webm" => vec!["-c:v", "libvpx-vp9", "-pix_fmt", "yuv420p", "-b:v", "1M", "-c:a", "libopus", "-b:a", "128k"],
+            "webp" => vec!["-c:v", "libwebp_anim", "-quality", "75", "-loop", "0"],
+            _ => vec!["-c:v", "libx264", "-pix_fmt", "yuv420p", "-c:a", "aac", "-b:a", "128k", "-movflags", "+faststart"]
+        }
+    }
+
+    /// Can the output container hold audio
+    pub fn supports_audio(output: &Path) -> bool {
+        !matches!(&FFmpeg::extension(output)[..], "webp" | "gif" | "apng")
+    }
+
+    /// Lowercase extension of path
+    fn extension(path: &Path) -> String {
+        path.extension().map(|e| e.to_string_lossy().to_ascii_lowercase()).unwrap_or_default()
+    }
+
+    /// Spawn decoder outputting raw RGB frames of video starting at position
+    pub fn decoder(&self, path: impl AsRef<Path>, start: Duration, width: u32, height: u32) -> Result<FrameDecoder, Error> {
+        let mut child = self.ffmpeg(false, true)
+            .arg("-ss").arg(start.as_secs_f64().to_string())
+            .arg("-i").arg(path.as_ref().as_os_str())
+            .args(["-an", "-f", "rawvideo", "-pix_fmt", "rgb24"])
+            .arg("-s").arg(format!("{width}x{height}"))
+            .arg("pipe:1")
+            .spawn()?;
+        let stdout = child.stdout.take().ok_or(anyhow!("Missing ffmpeg stdout"))?;
+        Ok(FrameDecoder { child, stdout: BufReader::new(stdout), width, height })
+    }
+
+    /// Spawn decoder outputting the audio track as f32 samples
+    pub fn audio_stream(&self, path: impl AsRef<Path>, sample_rate: u32, channels: u16) -> Result<AudioStream, Error> {
+        let mut child = self.ffmpeg(false, true)
+            .arg("-i").arg(path.as_ref().as_os_str())
+            .args(["-vn", "-f", "f32le"])
+            .arg("-ar").arg(sample_rate.to_string())
+            .arg("-ac").arg(channels.to_string())
+            .arg("pipe:1")
+            .spawn()?;
+        let stdout = child.stdout.take().ok_or(anyhow!("Missing ffmpeg stdout"))?;
+        Ok(AudioStream { child, stdout: BufReader::new(stdout), sample_rate, channels })
+    }
+
+    /// Check if the file has an audio stream
+    pub fn has_audio(&self, path: impl AsRef<Path>) -> Result<bool, Error> {
+        Ok(self.probe(path)?.has_audio())
+    }
+
     /// Use ffprobe to get video metadata
     pub fn video_meta(&self, path: impl AsRef<Path>) -> Result<VideoMeta, Error> {
-        let output = String::from_utf8_lossy(&Command::new(&self.ffprobe)
-            .args(["-v", "error", "-select_streams", "v:0", "-count_frames", "-show_entries", "stream=width,height,duration,nb_read_frames", "-of", "csv=p=0"])
-            .arg(path.as_ref().as_os_str())
-            .output()?
-            .stdout
-        ).to_string();
-        // Parse 
-        let mut i = output.trim().split(",");
-        let meta = VideoMeta {
-            width: i.next().ok_or(anyhow!("Missing width"))?.parse()?,
-            height: i.next().ok_or(anyhow!("Missing height"))?.parse()?,
-            duration: Duration::from_secs_f32(i.next().ok_or(anyhow!("Missing duration"))?.parse()?),
-            frames: i.next().ok_or(anyhow!("Missing frame count"))?.parse()?
-        };
-        Ok(meta)
+        VideoMeta::from_probe(&self.probe(path)?)
+    }
+}
+
+/// Running ffmpeg process encoding piped frames
+pub struct Encoder {
+    /// None = finished
+    child: Option<Child>,
+    stdin: Option<ChildStdin>,
+    width: u32,
+    height: u32,
+}
+
+impl Encoder {
+    /// Write next frame
+    pub fn write_frame(&mut self, frame: &DynamicImage) -> Result<(), Error> {
+        if frame.width() != self.width || frame.height() != self.height {
+            return Err(anyhow!("Invalid frame size: {}x{}, expected {}x{}", frame.width(), frame.height(), self.width, self.height));
+        }
+        let stdin = self.stdin.as_mut().ok_or(anyhow!("Encoder stdin closed"))?;
+        stdin.write_all(frame.to_rgb8().as_raw())?;
+        Ok(())
+    }
+
+    /// Close the input and wait for ffmpeg to finish
+    pub fn finish(mut self) -> Result<(), Error> {
+        std::mem::drop(self.stdin.take());
+        wait_output(self.child.take().ok_or(anyhow!("Encoder already finished"))?)
+    }
+}
+
+/// Unfinished encoder is killed, so it doesn't finalize a truncated file
+impl Drop for Encoder {
+    fn drop(&mut self) {
+        if let Some(mut child) = self.child.take() {
+            child.kill().ok();
+            child.wait().ok();
+        }
+    }
+}
+
+impl FrameSink for Encoder {
+    fn write_frame(&mut self, _index: usize, frame: DynamicImage) -> Result<(), Error> {
+        Encoder::write_frame(self, &frame)
+    }
+}
+
+/// Running ffmpeg process decoding frames into a pipe
+pub struct FrameDecoder {
+    child: Child,
+    stdout: BufReader<ChildStdout>,
+    width: u32,
+    height: u32,
+}
+
+impl FrameDecoder {
+    /// Read next frame, None = end of stream
+    pub fn read_frame(&mut self) -> Result<Option<RgbImage>, Error> {
+        let mut buffer = vec![0u8; self.width as usize * self.height as usize * 3];
+        match self.stdout.read_exact(&mut buffer) {
+            Ok(_) => Ok(Some(RgbImage::from_raw(self.width, self.height, buffer).unwrap())),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(e.into())
+        }
+    }
+}
+
+impl Drop for FrameDecoder {
+    fn drop(&mut self) {
+        self.child.kill().ok();
+        self.child.wait().ok();
+    }
+}
+
+/// Running ffmpeg process decoding audio as f32le into a pipe
+pub struct AudioStream {
+    child: Child,
+    stdout: BufReader<ChildStdout>,
+    sample_rate: u32,
+    channels: u16,
+}
+
+impl Iterator for AudioStream {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let mut buffer = [0u8; 4];
+        self.stdout.read_exact(&mut buffer).ok()?;
+        Some(f32::from_le_bytes(buffer))
+    }
+}
+
+impl Source for AudioStream {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+impl Drop for AudioStream {
+    fn drop(&mut self) {
+        self.child.kill().ok();
+        self.child.wait().ok();
     }
 }
 
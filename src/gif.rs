@@ -0,0 +1,374 @@
+use std::borrow::Cow;
+use std::path::Path;
+use anyhow::Error;
+use color_quant::NeuQuant;
+use image::{DynamicImage, RgbaImage};
+use serde::{Serialize, Deserialize};
+
+use crate::editor::FrameSink;
+use crate::ffmpeg::FFmpeg;
+use crate::time::Rational;
+
+/// Browsers treat shorter delays as 10cs
+const MIN_DELAY: u64 = 2;
+/// Max pixels used to train the global palette
+const MAX_PALETTE_SAMPLES: usize = 1_000_000;
+
+/// Where are palettes generated from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum PaletteMode {
+    /// Single palette from all frames, keeps every frame in memory until finished
+    #[default]
+    Global,
+    /// Palette for each frame, better colors but bigger file
+    PerFrame,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Dither {
+    None,
+    /// Error diffusion, best looking
+    #[default]
+    FloydSteinberg,
+    /// 4x4 Bayer matrix, compresses better than error diffusion
+    Ordered,
+}
+
+/// GIF & WebP encoding options
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GifOptions {
+    pub palette: PaletteMode,
+    pub dither: Dither,
+    /// Colors in palette, 2 -> 256
+    pub colors: usize,
+    /// Quantizer sampling factor, 1 = best & slowest, 30 = fastest
+    pub quality: i32,
+    /// Amount of repeats, 0 = forever
+    pub loops: u16,
+    /// Skip frames until the file fits into bytes
+    pub max_size: Option<u64>,
+}
+
+impl Default for GifOptions {
+    fn default() -> Self {
+        GifOptions { palette: PaletteMode::Global, dither: Dither::FloydSteinberg, colors: 256, quality: 10, loops: 0, max_size: None }
+    }
+}
+
+impl GifOptions {
+    /// Set palette mode
+    pub fn palette(mut self, palette: PaletteMode) -> Self {
+        self.palette = palette;
+        self
+    }
+
+    /// Set dithering
+    pub fn dither(mut self, dither: Dither) -> Self {
+        self.dither = dither;
+        self
+    }
+
+    /// Set palette size (2 -> 256)
+    pub fn colors(mut self, colors: usize) -> Self {
+        self.colors = colors.clamp(2, 256);
+        self
+    }
+
+    /// Set quantizer quality, 1 = best, 30 = fastest
+    pub fn quality(mut self, quality: i32) -> Self {
+        self.quality = quality.clamp(1, 30);
+        self
+    }
+
+    /// Set amount of repeats, 0 = forever
+    pub fn loops(mut self, loops: u16) -> Self {
+        self.loops = loops;
+        self
+    }
+
+    /// Drop frames until the file fits into bytes
+    pub fn max_size(mut self, max_size: u64) -> Self {
+        self.max_size = Some(max_size);
+        self
+    }
+}
+
+/// Frame after quantization
+struct IndexedFrame {
+    pixels: Vec<u8>,
+    /// None = global palette
+    palette: Option<Vec<u8>>,
+}
+
+/// Frame sink encoding animated GIF, call `finish` once all frames are written
+pub struct GifSink {
+    width: u16,
+    height: u16,
+    fps: Rational,
+    options: GifOptions,
+    /// Frames waiting for the global palette
+    raw: Vec<RgbaImage>,
+    frames: Vec<IndexedFrame>,
+}
+
+impl GifSink {
+    /// Create new sink for frames of size & frame rate
+    pub fn new(width: u32, height: u32, fps: impl Into<Rational>, options: GifOptions) -> Result<GifSink, Error> {
+        if width > u16::MAX as u32 || height > u16::MAX as u32 {
+            return Err(anyhow!("GIF can't be larger than 65535x65535: {width}x{height}"));
+        }
+        Ok(GifSink { width: width as u16, height: height as u16, fps: fps.into(), options, raw: vec![], frames: vec![] })
+    }
+
+    /// Quantize & encode all frames into file, returns the final size in bytes
+    pub fn finish(mut self, output: impl AsRef<Path>) -> Result<u64, Error> {
+        // Global palette from all frames
+        let mut global = vec![0u8; 3];
+        if !self.raw.is_empty() {
+            let total = self.raw.len() * self.raw[0].as_raw().len() / 4;
+            let step = total.div_ceil(MAX_PALETTE_SAMPLES);
+            let samples = self.raw.iter()
+                .flat_map(|f| f.as_raw().chunks_exact(4).step_by(step))
+                .flatten()
+                .copied()
+                .collect::<Vec<u8>>();
+            let quant = NeuQuant::new(self.options.quality, self.options.colors, &samples);
+            for frame in std::mem::take(&mut self.raw) {
+                self.frames.push(IndexedFrame { pixels: quantize(&frame, &quant, self.options.dither), palette: None });
+            }
+            global = quant.color_map_rgb();
+        }
+
+        // Drop frames until it fits
+        let mut skip = 1;
+        loop {
+            let data = self.encode(&global, skip)?;
+            let size = data.len() as u64;
+            match next_skip(self.options.max_size, size, skip, self.frames.len()) {
+                Some(next) => skip = next,
+                None => {
+                    std::fs::write(output, &data)?;
+                    return Ok(size);
+                }
+            }
+        }
+    }
+
+    /// Encode every nth frame
+    fn encode(&self, global: &[u8], skip: usize) -> Result<Vec<u8>, Error> {
+        // Centiseconds since start of frame, rounded
+        let time = |i: usize| (i as u64 * 200 * self.fps.den as u64 + self.fps.num as u64) / (2 * self.fps.num as u64);
+        let mut kept: Vec<usize> = vec![];
+        for i in (0..self.frames.len()).step_by(skip) {
+            if kept.last().map(|l| time(i) - time(*l) >= MIN_DELAY).unwrap_or(true) {
+                kept.push(i);
+            }
+        }
+
+        let mut data = vec![];
+        let mut encoder = ::gif::Encoder::new(&mut data, self.width, self.height, global)?;
+        encoder.set_repeat(match self.options.loops {
+            0 => ::gif::Repeat::Infinite,
+            n => ::gif::Repeat::Finite(n)
+        })?;
+        for (j, i) in kept.iter().enumerate() {
+            let next = kept.get(j + 1).copied().unwrap_or(self.frames.len());
+            let frame = &self.frames[*i];
+            encoder.write_frame(&::gif::Frame {
+                width: self.width,
+                height: self.height,
+                delay: (time(next) - time(*i)).max(MIN_DELAY) as u16,
+                palette: frame.palette.clone(),
+                buffer: Cow::Borrowed(&frame.pixels),
+                ..Default::default()
+            })?;
+        }
+        std::mem::drop(encoder);
+        Ok(data)
+    }
+}
+
+impl FrameSink for GifSink {
+    fn write_frame(&mut self, _index: usize, frame: DynamicImage) -> Result<(), Error> {
+        if frame.width() != self.width as u32 || frame.height() != self.height as u32 {
+            return Err(anyhow!("Invalid frame size: {}x{}, expected {}x{}", frame.width(), frame.height(), self.width, self.height));
+        }
+        let frame = frame.into_rgba8();
+        match self.options.palette {
+            PaletteMode::Global => self.raw.push(frame),
+            PaletteMode::PerFrame => {
+                let quant = NeuQuant::new(self.options.quality, self.options.colors, frame.as_raw());
+                let pixels = quantize(&frame, &quant, self.options.dither);
+                self.frames.push(IndexedFrame { pixels, palette: Some(quant.color_map_rgb()) });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Frame sink encoding animated WebP with ffmpeg (libwebp), call `finish` once all frames are written.
+/// Palette & dithering options don't apply.
+pub struct WebpSink {
+    width: u32,
+    height: u32,
+    fps: Rational,
+    options: GifOptions,
+    /// Kept until finished, so frames can be dropped to fit the max size
+    frames: Vec<DynamicImage>,
+}
+
+impl WebpSink {
+    /// Lossy quality 0 -> 100
+    const QUALITY: u8 = 75;
+
+    /// Create new sink for frames of size & frame rate
+    pub fn new(width: u32, height: u32, fps: impl Into<Rational>, options: GifOptions) -> WebpSink {
+        WebpSink { width, height, fps: fps.into(), options, frames: vec![] }
+    }
+
+    /// Encode all frames into file, returns the final size in bytes
+    pub fn finish(self, ffmpeg: &FFmpeg, output: impl AsRef<Path>) -> Result<u64, Error> {
+        if !ffmpeg.has_encoder("libwebp_anim")? {
+            return Err(anyhow!("ffmpeg was built without libwebp, can't encode animated WebP"));
+        }
+        let output = output.as_ref();
+        let args = ["-c:v".to_string(), "libwebp_anim".to_string(), "-quality".to_string(), Self::QUALITY.to_string(), "-loop".to_string(), self.options.loops.to_string()];
+        let mut skip = 1;
+        loop {
+            // Every nth frame at nth of the frame rate keeps the timing
+            let fps = Rational::new(self.fps.num, self.fps.den * skip as i64);
+            let mut encoder = ffmpeg.encoder(self.width, self.height, fps, None, output, &args)?;
+            for frame in self.frames.iter().step_by(skip) {
+                encoder.write_frame(frame)?;
+            }
+            encoder.finish()?;
+            let size = std::fs::metadata(output)?.len();
+            match next_skip(self.options.max_size, size, skip, self.frames.len()) {
+                Some(next) => skip = next,
+                None => return Ok(size)
+            }
+        }
+    }
+}
+
+impl FrameSink for WebpSink {
+    fn write_frame(&mut self, _index: usize, frame: DynamicImage) -> Result<(), Error> {
+        if frame.width() != self.width || frame.height() != self.height {
+            return Err(anyhow!("Invalid frame size: {}x{}, expected {}x{}", frame.width(), frame.height(), self.width, self.height));
+        }
+        self.frames.push(DynamicImage::ImageRgb8(frame.into_rgb8()));
+        Ok(())
+    }
+}
+
+/// Skip for the next attempt if the file is larger than max size, None = done
+fn next_skip(max_size: Option<u64>, size: u64, skip: usize, frames: usize) -> Option<usize> {
+    let max_size = max_size?;
+    if size <= max_size {
+        return None;
+    }
+    if skip >= frames {
+        warn!("Animation doesn't fit into the target size: {size} bytes");
+        return None;
+    }
+    let skip = ((skip as f64 * size as f64 / max_size as f64).ceil() as usize).max(skip + 1).min(frames);
+    debug!("Animation is {size} bytes, retrying with every {skip}. frame");
+    Some(skip)
+}
+
+/// Map frame to palette indices, alpha is ignored
+fn quantize(frame: &RgbaImage, quant: &NeuQuant, dither: Dither) -> Vec<u8> {
+    let (w, h) = (frame.width() as usize, frame.height() as usize);
+    let index = |rgb: [f32; 3]| {
+        let pixel = [rgb[0].clamp(0.0, 255.0) as u8, rgb[1].clamp(0.0, 255.0) as u8, rgb[2].clamp(0.0, 255.0) as u8, 255];
+        quant.index_of(&pixel)
+    };
+    let rgb = |x: usize, y: usize| {
+        let p = frame.get_pixel(x as u32, y as u32);
+        [p[0] as f32, p[1] as f32, p[2] as f32]
+    };
+    match dither {
+        Dither::None => (0..h).flat_map(|y| (0..w).map(move |x| (x, y))).map(|(x, y)| index(rgb(x, y)) as u8).collect(),
+        Dither::Ordered => {
+            const BAYER: [[f32; 4]; 4] = [[0.0, 8.0, 2.0, 10.0], [12.0, 4.0, 14.0, 6.0], [3.0, 11.0, 1.0, 9.0], [15.0, 7.0, 13.0, 5.0]];
+            // Spread of the threshold, roughly the distance between palette colors
+            let spread = 256.0 / (quant.color_map_rgb().len() as f32 / 3.0).cbrt();
+            (0..h).flat_map(|y| (0..w).map(move |x| (x, y))).map(|(x, y)| {
+                let t = (BAYER[y % 4][x % 4] + 0.5) / 16.0 - 0.5;
+                index(rgb(x, y).map(|c| c + t * spread)) as u8
+            }).collect()
+        },
+        Dither::FloydSteinberg => {
+            let mut output = Vec::with_capacity(w * h);
+            // Error of current & next row, padded by one on both sides
+            let mut current = vec![[0f32; 3]; w + 2];
+            let mut next = vec![[0f32; 3]; w + 2];
+            for y in 0..h {
+                for x in 0..w {
+                    let mut value = rgb(x, y);
+                    for c in 0..3 {
+                        value[c] += current[x + 1][c];
+                    }
+                    let i = index(value);
+                    let color = quant.lookup(i).unwrap_or([0, 0, 0, 255]);
+                    for c in 0..3 {
+                        let error = value[c].clamp(0.0, 255.0) - color[c] as f32;
+                        current[x + 2][c] += error * 7.0 / 16.0;
+                        next[x][c] += error * 3.0 / 16.0;
+                        next[x + 1][c] += error * 5.0 / 16.0;
+                        next[x + 2][c] += error / 16.0;
+                    }
+                    output.push(i as u8);
+                }
+                std::mem::swap(&mut current, &mut next);
+                next.iter_mut().for_each(|e| *e = [0.0; 3]);
+            }
+            output
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::RgbImage;
+    use super::*;
+
+    /// Encode a second of gray frames, returns delays of the frames and the loop count
+    fn encode(fps: Rational, options: GifOptions) -> (Vec<u16>, u16) {
+        let frames = fps.as_f64().round() as usize;
+        let mut sink = GifSink::new(4, 4, fps, options.palette(PaletteMode::PerFrame)).unwrap();
+        for i in 0..frames {
+            sink.write_frame(i, DynamicImage::from(RgbImage::from_pixel(4, 4, image::Rgb([i as u8 * 4; 3])))).unwrap();
+        }
+        let data = sink.encode(&[0; 3], 1).unwrap();
+
+        let mut decoder = ::gif::Decoder::new(data.as_slice()).unwrap();
+        let mut delays = vec![];
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            delays.push(frame.delay);
+        }
+        // Application extension, loop count follows the sub-block id
+        let netscape = data.windows(11).position(|w| w == b"NETSCAPE2.0").unwrap() + 13;
+        (delays, u16::from_le_bytes([data[netscape], data[netscape + 1]]))
+    }
+
+    #[test]
+    fn frame_delays() {
+        // 3.33cs per frame, rounding carried over so the second stays a second
+        let (delays, loops) = encode(Rational::new(30, 1), GifOptions::default());
+        assert_eq!(delays.len(), 30);
+        assert!(delays.iter().all(|d| *d == 3 || *d == 4));
+        assert_eq!(delays.iter().map(|d| *d as u32).sum::<u32>(), 100);
+        assert_eq!(loops, 0);
+
+        let (delays, _) = encode(Rational::new(30000, 1001), GifOptions::default());
+        assert_eq!(delays.iter().map(|d| *d as u32).sum::<u32>(), 100);
+
+        // Too short delays are merged, the length stays
+        let (delays, loops) = encode(Rational::new(60, 1), GifOptions::default().loops(3));
+        assert!(delays.len() <= 50);
+        assert!(delays.iter().all(|d| *d >= MIN_DELAY as u16));
+        assert_eq!(delays.iter().map(|d| *d as u32).sum::<u32>(), 100);
+        assert_eq!(loops, 3);
+    }
+}
@@ -0,0 +1,232 @@
+use std::f32::consts::PI;
+use std::time::Duration;
+use lerp::Lerp;
+use serde::{Serialize, Deserialize};
+
+/// Easing curve of a segment between two keyframes
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub enum Easing {
+    #[default]
+    Linear,
+    EaseIn,
+    EaseOut,
+    EaseInOut,
+    /// Control points (x1, y1, x2, y2) like CSS cubic-bezier()
+    CubicBezier(f32, f32, f32, f32),
+    /// Hold the value until the next keyframe
+    Step,
+    Bounce,
+    Elastic
+}
+
+impl Easing {
+    /// Map linear progress 0 -> 1 to eased progress
+    pub fn ease(&self, t: f32) -> f32 {
+        let t = t.clamp(0.0, 1.0);
+        match *self {
+            Easing::Linear => t,
+            Easing::EaseIn => t * t * t,
+            Easing::EaseOut => 1.0 - (1.0 - t).powi(3),
+            Easing::EaseInOut => match t < 0.5 {
+                true => 4.0 * t * t * t,
+                false => 1.0 - (-2.0 * t + 2.0).powi(3) / 2.0
+            },
+            Easing::CubicBezier(x1, y1, x2, y2) => cubic_bezier(x1, y1, x2, y2, t),
+            Easing::Step => match t >= 1.0 {
+                true => 1.0,
+                false => 0.0
+            },
+            Easing::Bounce => bounce(t),
+            Easing::Elastic => match t {
+                t if t <= 0.0 => 0.0,
+                t if t >= 1.0 => 1.0,
+                t => 2.0f32.powf(-10.0 * t) * ((t * 10.0 - 0.75) * (2.0 * PI / 3.0)).sin() + 1.0
+            },
+        }
+    }
+}
+
+/// Ease out bounce
+fn bounce(t: f32) -> f32 {
+    let (n, d) = (7.5625, 2.75);
+    if t < 1.0 / d {
+        n * t * t
+    } else if t < 2.0 / d {
+        let t = t - 1.5 / d;
+        n * t * t + 0.75
+    } else if t < 2.5 / d {
+        let t = t - 2.25 / d;
+        n * t * t + 0.9375
+    } else {
+        let t = t - 2.625 / d;
+        n * t * t + 0.984375
+    }
+}
+
+/// Evaluate cubic bezier easing with (0, 0) and (1, 1) endpoints
+fn cubic_bezier(x1: f32, y1: f32, x2: f32, y2: f32, x: f32) -> f32 {
+    let curve = |a: f32, b: f32, t: f32| 3.0 * a * t * (1.0 - t).powi(2) + 3.0 * b * t * t * (1.0 - t) + t * t * t;
+    // Find t for x with bisection
+    let (mut lo, mut hi) = (0.0f32, 1.0f32);
+    for _ in 0..32 {
+        let mid = (lo + hi) / 2.0;
+        if curve(x1, x2, mid) < x {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+    curve(y1, y2, (lo + hi) / 2.0)
+}
+
+/// Value which can be interpolated between keyframes
+pub trait Interpolate: Copy {
+    fn interpolate(&self, other: &Self, t: f32) -> Self;
+}
+
+impl Interpolate for f32 {
+    fn interpolate(&self, other: &Self, t: f32) -> Self {
+        self.lerp(*other, t)
+    }
+}
+
+impl Interpolate for (f32, f32) {
+    fn interpolate(&self, other: &Self, t: f32) -> Self {
+        (self.0.lerp(other.0, t), self.1.lerp(other.1, t))
+    }
+}
+
+impl Interpolate for Duration {
+    fn interpolate(&self, other: &Self, t: f32) -> Self {
+        let (a, b) = (self.as_nanos() as f64, other.as_nanos() as f64);
+        Duration::from_nanos((a + (b - a) * t as f64).max(0.0) as u64)
+    }
+}
+
+/// Single keyframe
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Keyframe<T> {
+    /// Relative to the start of the layer
+    pub time: Duration,
+    pub value: T,
+    /// Easing of the segment starting at this keyframe
+    #[serde(default)]
+    pub easing: Easing,
+}
+
+/// Keyframe animation track
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(from = "KeyframesRepr<T>")]
+pub struct Keyframes<T> {
+    keys: Vec<Keyframe<T>>
+}
+
+/// Keys of hand-written files may be in any order
+#[derive(Deserialize)]
+struct KeyframesRepr<T> {
+    keys: Vec<Keyframe<T>>
+}
+
+impl<T> From<KeyframesRepr<T>> for Keyframes<T> {
+    fn from(mut value: KeyframesRepr<T>) -> Self {
+        // Stable, keys at the same time keep their order like with `key`
+        value.keys.sort_by_key(|k| k.time);
+        Keyframes { keys: value.keys }
+    }
+}
+
+impl<T: Interpolate> Keyframes<T> {
+    /// Create new empty track
+    pub fn new() -> Keyframes<T> {
+        Keyframes { keys: vec![] }
+    }
+
+    /// Track with a single constant value
+    pub fn constant(value: T) -> Keyframes<T> {
+        Keyframes::new().key(Duration::ZERO, value, Easing::Linear)
+    }
+
+    /// Add new keyframe, easing is used until the next keyframe
+    pub fn key(mut self, time: Duration, value: T, easing: Easing) -> Self {
+        let index = self.keys.partition_point(|k| k.time <= time);
+        self.keys.insert(index, Keyframe { time, value, easing });
+        self
+    }
+
+    /// Get all keyframes
+    pub fn keys(&self) -> &[Keyframe<T>] {
+        &self.keys
+    }
+
+    /// Get value at time, values before the first and after the last keyframe are held
+    pub fn value(&self, time: Duration) -> Option<T> {
+        let index = self.keys.partition_point(|k| k.time <= time);
+        if index == 0 {
+            return self.keys.first().map(|k| k.value);
+        }
+        let from = &self.keys[index - 1];
+        let to = match self.keys.get(index) {
+            Some(to) => to,
+            None => return Some(from.value)
+        };
+        let t = (time - from.time).as_secs_f32() / (to.time - from.time).as_secs_f32();
+        Some(from.value.interpolate(&to.value, from.easing.ease(t)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(s: f32) -> Duration {
+        Duration::from_secs_f32(s)
+    }
+
+    #[test]
+    fn values() {
+        assert_eq!(Keyframes::<f32>::new().value(secs(1.0)), None);
+        assert_eq!(Keyframes::constant(0.5).value(secs(3.0)), Some(0.5));
+
+        // Inserted out of order
+        let track = Keyframes::new()
+            .key(secs(2.0), 10.0, Easing::Step)
+            .key(secs(1.0), 0.0, Easing::Linear)
+            .key(secs(3.0), 20.0, Easing::Linear);
+        assert_eq!(track.value(secs(0.0)), Some(0.0));
+        assert_eq!(track.value(secs(1.0)), Some(0.0));
+        assert_eq!(track.value(secs(1.5)), Some(5.0));
+        assert_eq!(track.value(secs(2.0)), Some(10.0));
+        assert_eq!(track.value(secs(2.9)), Some(10.0));
+        assert_eq!(track.value(secs(3.0)), Some(20.0));
+        assert_eq!(track.value(secs(10.0)), Some(20.0));
+    }
+
+    #[test]
+    fn unsorted_json() {
+        let json = r#"{"keys": [
+            {"time": {"secs": 3, "nanos": 0}, "value": 1.0},
+            {"time": {"secs": 1, "nanos": 0}, "value": 2.0},
+            {"time": {"secs": 2, "nanos": 0}, "value": 3.0}
+        ]}"#;
+        let track: Keyframes<f32> = serde_json::from_str(json).unwrap();
+        assert_eq!(track.keys().iter().map(|k| k.value).collect::<Vec<_>>(), [2.0, 3.0, 1.0]);
+        assert_eq!(track.value(secs(0.0)), Some(2.0));
+        assert_eq!(track.value(secs(1.5)), Some(2.5));
+        assert_eq!(track.value(secs(3.5)), Some(1.0));
+        assert_eq!(serde_json::from_str::<Keyframes<f32>>(&serde_json::to_string(&track).unwrap()).unwrap(), track);
+    }
+
+    #[test]
+    fn easings() {
+        let track = |easing| Keyframes::new().key(secs(0.0), (0.0, 0.0), easing).key(secs(1.0), (1.0, 2.0), Easing::Linear);
+        assert_eq!(track(Easing::Linear).value(secs(0.5)), Some((0.5, 1.0)));
+        assert!(track(Easing::EaseIn).value(secs(0.5)).unwrap().0 < 0.5);
+        assert!(track(Easing::EaseOut).value(secs(0.5)).unwrap().0 > 0.5);
+        let (x, _) = track(Easing::CubicBezier(0.0, 0.0, 1.0, 1.0)).value(secs(0.5)).unwrap();
+        assert!((x - 0.5).abs() < 1e-3);
+        for easing in [Easing::EaseInOut, Easing::Bounce, Easing::Elastic] {
+            assert_eq!(easing.ease(0.0), 0.0);
+            assert_eq!(easing.ease(1.0), 1.0);
+        }
+    }
+}
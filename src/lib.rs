@@ -4,7 +4,22 @@
 pub mod editor;
 pub mod source;
 pub mod ffmpeg;
+pub mod cache;
+pub mod blend;
+pub mod keyframe;
+pub mod transition;
+pub mod progress;
+pub mod report;
+pub mod project;
+pub mod text;
+pub mod probe;
+pub mod time;
+pub mod gif;
+pub mod master;
+pub mod stretch;
+pub mod dsp;
 
 pub use editor::Editor;
 pub use ffmpeg::FFmpeg;
-pub use source::FileLoader;
\ No newline at end of file
+pub use source::FileLoader;
+pub use project::Project;
\ No newline at end of file
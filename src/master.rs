@@ -0,0 +1,345 @@
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+use std::time::Duration;
+use serde::{Serialize, Deserialize};
+
+use crate::dsp::Biquad;
+
+/// Peak control of the final mix
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Limiter {
+    /// Leave peaks as they are, can clip once encoded
+    None,
+    /// Saturate samples above the knee (0 -> 1 of the ceiling), colors the sound
+    SoftClip { knee: f32 },
+    /// Transparent gain reduction, reacts before the peak
+    LookAhead { lookahead: Duration, release: Duration },
+}
+
+impl Default for Limiter {
+    fn default() -> Self {
+        Limiter::LookAhead { lookahead: Duration::from_millis(5), release: Duration::from_millis(100) }
+    }
+}
+
+/// Processing of the final mix: headroom -> loudness normalization -> limiter
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MasterBus {
+    /// Gain applied to the sum of all layers in dB, negative = more headroom
+    pub gain: f32,
+    /// Target integrated loudness in LUFS (EBU R128), e.g. -14 for streaming
+    pub normalize: Option<f32>,
+    pub limiter: Limiter,
+    /// Max peak in dBFS
+    pub ceiling: f32,
+}
+
+impl Default for MasterBus {
+    fn default() -> Self {
+        MasterBus { gain: 0.0, normalize: None, limiter: Limiter::default(), ceiling: -1.0 }
+    }
+}
+
+impl MasterBus {
+    /// Passes the mix through untouched
+    pub fn bypass() -> MasterBus {
+        MasterBus { gain: 0.0, normalize: None, limiter: Limiter::None, ceiling: 0.0 }
+    }
+
+    /// Reduce the level of the mix by dB
+    pub fn headroom(mut self, db: f32) -> Self {
+        self.gain = -db;
+        self
+    }
+
+    /// Normalize to integrated loudness in LUFS
+    pub fn normalize(mut self, lufs: f32) -> Self {
+        self.normalize = Some(lufs);
+        self
+    }
+
+    /// Set the limiter
+    pub fn limiter(mut self, limiter: Limiter) -> Self {
+        self.limiter = limiter;
+        self
+    }
+
+    /// Set the max peak in dBFS
+    pub fn ceiling(mut self, db: f32) -> Self {
+        self.ceiling = db;
+        self
+    }
+
+    /// Process interleaved samples of the mix
+    pub fn process(&self, samples: &mut [f32], sample_rate: u32, channels: u16) {
+        let mut gain = db_to_gain(self.gain);
+        if let Some(target) = self.normalize {
+            scale(samples, gain);
+            gain = 1.0;
+            match integrated_loudness(samples, sample_rate, channels) {
+                Some(loudness) => {
+                    debug!("Normalizing loudness from {loudness:.1} LUFS to {target:.1} LUFS");
+                    gain = db_to_gain(target - loudness as f32);
+                },
+                None => debug!("Mix is silent, skipping normalization")
+            }
+        }
+        scale(samples, gain);
+
+        let ceiling = db_to_gain(self.ceiling);
+        match self.limiter {
+            Limiter::None => {},
+            Limiter::SoftClip { knee } => soft_clip(samples, ceiling, knee),
+            Limiter::LookAhead { lookahead, release } => limit(samples, sample_rate, channels, ceiling, lookahead, release),
+        }
+    }
+}
+
+/// Sidechain compression, layers marked as ducked get quieter whenever the key layers are loud
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Ducking {
+    /// Level of the key in dBFS above which the ducked layers are reduced
+    pub threshold: f32,
+    /// Compression above the threshold, 4 = key 8dB over threshold -> ducked reduced by 6dB
+    pub ratio: f32,
+    /// How fast the ducked layers go down
+    pub attack: Duration,
+    /// How fast they come back
+    pub release: Duration,
+}
+
+impl Default for Ducking {
+    fn default() -> Self {
+        Ducking { threshold: -30.0, ratio: 4.0, attack: Duration::from_millis(20), release: Duration::from_millis(400) }
+    }
+}
+
+impl Ducking {
+    /// Set the threshold in dBFS
+    pub fn threshold(mut self, db: f32) -> Self {
+        self.threshold = db;
+        self
+    }
+
+    /// Set the compression ratio (>= 1)
+    pub fn ratio(mut self, ratio: f32) -> Self {
+        self.ratio = ratio.max(1.0);
+        self
+    }
+
+    /// Set the attack time
+    pub fn attack(mut self, attack: Duration) -> Self {
+        self.attack = attack;
+        self
+    }
+
+    /// Set the release time
+    pub fn release(mut self, release: Duration) -> Self {
+        self.release = release;
+        self
+    }
+
+    /// Create envelope follower for sample rate
+    pub(crate) fn follower(&self, sample_rate: u32) -> Follower {
+        let coefficient = |time: Duration| (-1.0 / (time.as_secs_f32() * sample_rate as f32).max(1.0)).exp();
+        Follower { ducking: self.clone(), attack: coefficient(self.attack), release: coefficient(self.release), envelope: 0.0 }
+    }
+}
+
+/// Tracks the peak level of the key, returns the gain of the ducked layers
+pub(crate) struct Follower {
+    ducking: Ducking,
+    attack: f32,
+    release: f32,
+    envelope: f32,
+}
+
+impl Follower {
+    /// Feed one frame of the key, get the gain for the same frame
+    pub(crate) fn process(&mut self, key: &[f32]) -> f32 {
+        let level = key.iter().fold(0f32, |a, s| a.max(s.abs()));
+        let coefficient = if level > self.envelope { self.attack } else { self.release };
+        self.envelope = level + (self.envelope - level) * coefficient;
+        let over = 20.0 * self.envelope.max(1e-6).log10() - self.ducking.threshold;
+        if over <= 0.0 {
+            return 1.0;
+        }
+        db_to_gain(-over * (1.0 - 1.0 / self.ducking.ratio.max(1.0)))
+    }
+}
+
+fn db_to_gain(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+fn scale(samples: &mut [f32], gain: f32) {
+    if gain != 1.0 {
+        samples.iter_mut().for_each(|s| *s *= gain);
+    }
+}
+
+/// Linear below knee, smoothly saturating towards the ceiling above it
+fn soft_clip(samples: &mut [f32], ceiling: f32, knee: f32) {
+    let threshold = ceiling * knee.clamp(0.0, 0.99);
+    let range = ceiling - threshold;
+    for sample in samples {
+        let level = sample.abs();
+        if level > threshold {
+            *sample = sample.signum() * (threshold + range * ((level - threshold) / range).tanh());
+        }
+    }
+}
+
+/// Gain is the lowest required one within the look-ahead window, smoothed so it never exceeds the required gain
+fn limit(samples: &mut [f32], sample_rate: u32, channels: u16, ceiling: f32, lookahead: Duration, release: Duration) {
+    let channels = channels.max(1) as usize;
+    let frames = samples.len() / channels;
+    let window = ((lookahead.as_secs_f64() * sample_rate as f64) as usize).max(1);
+    let required = samples.chunks(channels).map(|frame| {
+        let peak = frame.iter().fold(0f32, |a, s| a.max(s.abs()));
+        if peak > ceiling { ceiling / peak } else { 1.0 }
+    }).collect::<Vec<_>>();
+
+    // Sliding minimum over the following window
+    let mut minimum = vec![1.0; frames];
+    let mut queue: VecDeque<usize> = VecDeque::new();
+    for i in (0..frames).rev() {
+        while queue.back().map(|j| required[*j] >= required[i]).unwrap_or(false) {
+            queue.pop_back();
+        }
+        queue.push_back(i);
+        while queue.front().map(|j| *j >= i + window).unwrap_or(false) {
+            queue.pop_front();
+        }
+        minimum[i] = required[queue[0]];
+    }
+
+    // Average over the previous window ramps the gain down before the peak
+    let release = (-1.0 / (release.as_secs_f64() * sample_rate as f64).max(1.0)).exp() as f32;
+    let (mut sum, mut gain) = (0.0, 1.0f32);
+    for i in 0..frames {
+        sum += minimum[i] as f64;
+        if i >= window {
+            sum -= minimum[i - window] as f64;
+        }
+        let target = (sum / (i + 1).min(window) as f64) as f32;
+        gain = match target < gain {
+            true => target,
+            false => target + (gain - target) * release
+        };
+        // Averaging rounding can't exceed the required gain
+        let gain = gain.min(required[i]);
+        samples[i * channels..(i + 1) * channels].iter_mut().for_each(|s| *s *= gain);
+    }
+}
+
+/// Integrated loudness in LUFS according to ITU-R BS.1770 / EBU R128, None if silent
+pub fn integrated_loudness(samples: &[f32], sample_rate: u32, channels: u16) -> Option<f64> {
+    let channels = channels.max(1) as usize;
+    let frames = samples.len() / channels;
+    // 400ms blocks with 75% overlap
+    let block = (sample_rate as f64 * 0.4) as usize;
+    let step = block / 4;
+    if frames < block || step == 0 {
+        return None;
+    }
+
+    // K-weighted squared samples, summed per 100ms step
+    let mut steps = vec![vec![0f64; channels]; frames / step];
+    for c in 0..channels {
+        let mut filter = KWeighting::new(sample_rate as f64);
+        for (i, frame) in samples.chunks_exact(channels).enumerate() {
+            let s = filter.process(frame[c] as f64);
+            if let Some(sum) = steps.get_mut(i / step) {
+                sum[c] += s * s;
+            }
+        }
+    }
+    // Surround channels (5.1) are weighted, LFE is ignored
+    let weight = |c: usize| match (channels, c) {
+        (6, 3) => 0.0,
+        (6, 4) | (6, 5) => 1.41,
+        _ => 1.0
+    };
+    let blocks = steps.windows(4).map(|w| {
+        (0..channels).map(|c| weight(c) * w.iter().map(|s| s[c]).sum::<f64>() / block as f64).sum::<f64>()
+    }).collect::<Vec<f64>>();
+    let loudness = |power: f64| -0.691 + 10.0 * power.log10();
+
+    // Absolute & relative gate
+    let gated = |threshold: f64| {
+        let passed = blocks.iter().filter(|p| loudness(**p) > threshold).collect::<Vec<_>>();
+        match passed.is_empty() {
+            true => None,
+            false => Some(passed.iter().copied().sum::<f64>() / passed.len() as f64)
+        }
+    };
+    let relative = loudness(gated(-70.0)?) - 10.0;
+    Some(loudness(gated(relative.max(-70.0))?))
+}
+
+/// Pre-filter of BS.1770: high shelf followed by high pass
+struct KWeighting {
+    stages: [Biquad; 2],
+}
+
+impl KWeighting {
+    fn new(rate: f64) -> KWeighting {
+        // High shelf, +4dB above ~1.7kHz
+        let (f0, gain, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+        let k = (PI * f0 / rate).tan();
+        let vh = 10f64.powf(gain / 20.0);
+        let vb = vh.powf(0.4996667741545416);
+        let a0 = 1.0 + k / q + k * k;
+        let shelf = Biquad::new(
+            [(vh + vb * k / q + k * k) / a0, 2.0 * (k * k - vh) / a0, (vh - vb * k / q + k * k) / a0],
+            [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0]
+        );
+        // High pass at ~38Hz
+        let (f0, q) = (38.13547087602444, 0.5003270373238773);
+        let k = (PI * f0 / rate).tan();
+        let a0 = 1.0 + k / q + k * k;
+        let high_pass = Biquad::new([1.0, -2.0, 1.0], [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0]);
+        KWeighting { stages: [shelf, high_pass] }
+    }
+
+    fn process(&mut self, sample: f64) -> f64 {
+        self.stages.iter_mut().fold(sample, |s, stage| stage.process(s))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Stereo sine of given peak level in dBFS
+    fn sine(db: f32, frequency: f64, seconds: f64, sample_rate: u32) -> Vec<f32> {
+        let amplitude = db_to_gain(db) as f64;
+        (0..(seconds * sample_rate as f64) as usize)
+            .map(|i| (amplitude * (2.0 * PI * frequency * i as f64 / sample_rate as f64).sin()) as f32)
+            .flat_map(|s| [s, s])
+            .collect()
+    }
+
+    #[test]
+    fn loudness() {
+        // EBU Tech 3341: 1kHz stereo sine at -23 dBFS reads -23 LUFS
+        for sample_rate in [44100, 48000] {
+            let lufs = integrated_loudness(&sine(-20.0, 1000.0, 5.0, sample_rate), sample_rate, 2).unwrap();
+            assert!((lufs + 20.0).abs() < 0.1, "{lufs}");
+            let lufs = integrated_loudness(&sine(-23.0, 1000.0, 5.0, sample_rate), sample_rate, 2).unwrap();
+            assert!((lufs + 23.0).abs() < 0.1, "{lufs}");
+        }
+    }
+
+    #[test]
+    fn gating() {
+        assert_eq!(integrated_loudness(&vec![0.0; 96000 * 2], 48000, 2), None);
+        assert_eq!(integrated_loudness(&sine(-20.0, 1000.0, 0.2, 48000), 48000, 2), None);
+        // Quiet part below the relative gate doesn't lower the result
+        let mut samples = sine(-20.0, 1000.0, 5.0, 48000);
+        samples.extend(sine(-60.0, 1000.0, 5.0, 48000));
+        let lufs = integrated_loudness(&samples, 48000, 2).unwrap();
+        assert!((lufs + 20.0).abs() < 0.2, "{lufs}");
+    }
+}
@@ -0,0 +1,311 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use anyhow::Error;
+use serde::{Serialize, Deserialize};
+
+use crate::time::Rational;
+
+/// Everything ffprobe knows about a media file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MediaProbe {
+    pub streams: Vec<StreamInfo>,
+    pub format: FormatInfo,
+    pub chapters: Vec<Chapter>,
+}
+
+impl MediaProbe {
+    /// Parse output of `ffprobe -of json -show_format -show_streams -show_chapters`
+    pub fn from_json(data: &str) -> Result<MediaProbe, Error> {
+        let raw: RawProbe = serde_json::from_str(data)?;
+        Ok(MediaProbe {
+            streams: raw.streams.into_iter().map(RawStream::into_info).collect(),
+            format: raw.format.map(RawFormat::into_info).unwrap_or_default(),
+            chapters: raw.chapters.into_iter().map(RawChapter::into_info).collect(),
+        })
+    }
+
+    /// First video stream
+    pub fn video(&self) -> Option<&VideoStreamInfo> {
+        self.streams.iter().find_map(|s| match s {
+            StreamInfo::Video(v) => Some(v),
+            _ => None
+        })
+    }
+
+    /// First audio stream
+    pub fn audio(&self) -> Option<&AudioStreamInfo> {
+        self.streams.iter().find_map(|s| match s {
+            StreamInfo::Audio(a) => Some(a),
+            _ => None
+        })
+    }
+
+    /// Has at least one audio stream
+    pub fn has_audio(&self) -> bool {
+        self.audio().is_some()
+    }
+
+    /// Duration of the container, or the longest stream
+    pub fn duration(&self) -> Option<Duration> {
+        self.format.duration.or_else(|| {
+            self.streams.iter().filter_map(|s| match s {
+                StreamInfo::Video(v) => v.duration,
+                StreamInfo::Audio(a) => a.duration,
+                StreamInfo::Other { .. } => None
+            }).max()
+        })
+    }
+}
+
+/// Single stream
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum StreamInfo {
+    Video(VideoStreamInfo),
+    Audio(AudioStreamInfo),
+    /// Subtitles, data, attachments...
+    Other { index: usize, codec_type: String, codec: Option<String> },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VideoStreamInfo {
+    pub index: usize,
+    pub codec: String,
+    pub width: u32,
+    pub height: u32,
+    pub frame_rate: Rational,
+    pub pixel_format: Option<String>,
+    /// Rotation in degrees from the display matrix (counter clockwise)
+    pub rotation: i32,
+    pub sample_aspect_ratio: Option<Rational>,
+    pub duration: Option<Duration>,
+    /// Frame count from the container, if available
+    pub frames: Option<usize>,
+    pub tags: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AudioStreamInfo {
+    pub index: usize,
+    pub codec: String,
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub duration: Option<Duration>,
+    pub tags: HashMap<String, String>,
+}
+
+/// Container info
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FormatInfo {
+    pub format_name: String,
+    pub duration: Option<Duration>,
+    /// In bytes
+    pub size: Option<u64>,
+    pub bit_rate: Option<u64>,
+    pub tags: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Chapter {
+    pub id: i64,
+    pub start: Duration,
+    pub end: Duration,
+    pub title: Option<String>,
+}
+
+// ffprobe prints most numbers as strings
+
+#[derive(Deserialize)]
+struct RawProbe {
+    #[serde(default)]
+    streams: Vec<RawStream>,
+    format: Option<RawFormat>,
+    #[serde(default)]
+    chapters: Vec<RawChapter>,
+}
+
+#[derive(Deserialize)]
+struct RawStream {
+    index: usize,
+    codec_type: Option<String>,
+    codec_name: Option<String>,
+    width: Option<u32>,
+    height: Option<u32>,
+    r_frame_rate: Option<String>,
+    avg_frame_rate: Option<String>,
+    pix_fmt: Option<String>,
+    sample_aspect_ratio: Option<String>,
+    duration: Option<String>,
+    nb_frames: Option<String>,
+    sample_rate: Option<String>,
+    channels: Option<u16>,
+    #[serde(default)]
+    side_data_list: Vec<serde_json::Value>,
+    #[serde(default)]
+    tags: HashMap<String, String>,
+}
+
+impl RawStream {
+    fn into_info(self) -> StreamInfo {
+        let codec = self.codec_name.clone().unwrap_or_default();
+        let duration = parse_duration(&self.duration);
+        match self.codec_type.as_deref() {
+            Some("video") => {
+                // Average is more accurate for variable frame rate
+                let frame_rate = [&self.avg_frame_rate, &self.r_frame_rate].into_iter()
+                    .filter_map(|r| r.as_ref()?.parse::<Rational>().ok())
+                    .find(Rational::is_valid)
+                    .unwrap_or(Rational::new(25, 1));
+                // Display matrix, or legacy rotate tag (clockwise)
+                let rotation = self.side_data_list.iter()
+                    .find_map(|d| d.get("rotation")?.as_i64())
+                    .map(|r| r as i32)
+                    .or_else(|| self.tags.get("rotate").and_then(|r| r.parse::<i32>().ok()).map(|r| -r))
+                    .unwrap_or(0);
+                StreamInfo::Video(VideoStreamInfo {
+                    index: self.index,
+                    codec,
+                    width: self.width.unwrap_or(0),
+                    height: self.height.unwrap_or(0),
+                    frame_rate,
+                    pixel_format: self.pix_fmt,
+                    rotation,
+                    sample_aspect_ratio: self.sample_aspect_ratio.and_then(|r| r.parse().ok()).filter(Rational::is_valid),
+                    duration,
+                    frames: self.nb_frames.and_then(|f| f.parse().ok()).filter(|f| *f > 0),
+                    tags: self.tags,
+                })
+            },
+            Some("audio") => StreamInfo::Audio(AudioStreamInfo {
+                index: self.index,
+                codec,
+                sample_rate: self.sample_rate.and_then(|r| r.parse().ok()).unwrap_or(0),
+                channels: self.channels.unwrap_or(0),
+                duration,
+                tags: self.tags,
+            }),
+            codec_type => StreamInfo::Other {
+                index: self.index,
+                codec_type: codec_type.unwrap_or("unknown").to_string(),
+                codec: self.codec_name
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RawFormat {
+    format_name: Option<String>,
+    duration: Option<String>,
+    size: Option<String>,
+    bit_rate: Option<String>,
+    #[serde(default)]
+    tags: HashMap<String, String>,
+}
+
+impl RawFormat {
+    fn into_info(self) -> FormatInfo {
+        FormatInfo {
+            format_name: self.format_name.unwrap_or_default(),
+            duration: parse_duration(&self.duration),
+            size: self.size.and_then(|s| s.parse().ok()),
+            bit_rate: self.bit_rate.and_then(|b| b.parse().ok()),
+            tags: self.tags,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RawChapter {
+    id: i64,
+    start_time: Option<String>,
+    end_time: Option<String>,
+    #[serde(default)]
+    tags: HashMap<String, String>,
+}
+
+impl RawChapter {
+    fn into_info(mut self) -> Chapter {
+        Chapter {
+            id: self.id,
+            start: parse_duration(&self.start_time).unwrap_or_default(),
+            end: parse_duration(&self.end_time).unwrap_or_default(),
+            title: self.tags.remove("title"),
+        }
+    }
+}
+
+/// Parse seconds, ignoring N/A
+fn parse_duration(value: &Option<String>) -> Option<Duration> {
+    value.as_ref()?.parse::<f64>().ok().filter(|d| d.is_finite() && *d >= 0.0).map(Duration::from_secs_f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::source::VideoMeta;
+    use super::*;
+
+    /// Portrait phone clip, recorded anamorphic, trimmed from `ffprobe -of json -show_format -show_streams -show_chapters`
+    const PHONE: &str = r#"{
+        "streams": [
+            {
+                "index": 0, "codec_name": "h264", "codec_type": "video", "width": 1440, "height": 1080,
+                "sample_aspect_ratio": "4:3", "pix_fmt": "yuv420p",
+                "r_frame_rate": "30/1", "avg_frame_rate": "30000/1001",
+                "nb_frames": "N/A",
+                "tags": { "handler_name": "VideoHandle" },
+                "side_data_list": [
+                    { "side_data_type": "Display Matrix", "displaymatrix": "...", "rotation": -90 }
+                ]
+            },
+            {
+                "index": 1, "codec_name": "aac", "codec_type": "audio", "sample_rate": "48000", "channels": 2,
+                "duration": "12.480000", "tags": { "handler_name": "SoundHandle" }
+            },
+            { "index": 2, "codec_name": "bin_data", "codec_type": "data", "tags": {} }
+        ],
+        "chapters": [
+            { "id": 0, "start_time": "0.000000", "end_time": "5.000000", "tags": { "title": "Intro" } }
+        ],
+        "format": {
+            "format_name": "mov,mp4,m4a,3gp,3g2,mj2", "duration": "N/A", "size": "15849126", "bit_rate": "10153421",
+            "tags": { "com.apple.quicktime.make": "Apple" }
+        }
+    }"#;
+
+    #[test]
+    fn phone_clip() {
+        let probe = MediaProbe::from_json(PHONE).unwrap();
+        let video = probe.video().unwrap();
+        assert_eq!((video.width, video.height, video.rotation), (1440, 1080, -90));
+        assert_eq!(video.sample_aspect_ratio, Some(Rational::new(4, 3)));
+        assert_eq!(video.frame_rate, Rational::new(30000, 1001));
+        assert_eq!((video.duration, video.frames), (None, None));
+        assert_eq!(probe.audio().unwrap().sample_rate, 48000);
+        assert!(matches!(&probe.streams[2], StreamInfo::Other { codec_type, .. } if codec_type == "data"));
+        assert_eq!(probe.chapters[0].title.as_deref(), Some("Intro"));
+        assert_eq!(probe.format.size, Some(15849126));
+
+        // Square pixels first (1920x1080), then rotated to portrait; no video or container duration, longest stream
+        let meta = VideoMeta::from_probe(&probe).unwrap();
+        assert_eq!((meta.width, meta.height), (1080, 1920));
+        assert_eq!(meta.duration, Duration::from_millis(12480));
+        assert_eq!(meta.frames, 374);
+    }
+
+    #[test]
+    fn legacy_rotate_tag() {
+        let json = r#"{
+            "streams": [{
+                "index": 0, "codec_type": "video", "codec_name": "mpeg4", "width": 640, "height": 480,
+                "r_frame_rate": "25/1", "avg_frame_rate": "0/0", "sample_aspect_ratio": "0:1",
+                "duration": "4.000000", "nb_frames": "100", "tags": { "rotate": "270" }
+            }],
+            "format": { "format_name": "mov", "duration": "4.100000" }
+        }"#;
+        let probe = MediaProbe::from_json(json).unwrap();
+        let video = probe.video().unwrap();
+        assert_eq!((video.rotation, video.sample_aspect_ratio, video.frame_rate), (-270, None, Rational::new(25, 1)));
+        let meta = VideoMeta::from_probe(&probe).unwrap();
+        assert_eq!((meta.width, meta.height, meta.duration, meta.frames), (480, 640, Duration::from_secs(4), 100));
+    }
+}
@@ -0,0 +1,141 @@
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// What is being processed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// Units = frames
+    Video,
+    /// Units = samples (per channel)
+    Audio,
+    /// ffmpeg encoding, units = milliseconds of output
+    Encode,
+}
+
+/// Progress update
+#[derive(Debug, Clone)]
+pub struct Progress {
+    pub stage: Stage,
+    pub done: usize,
+    pub total: usize,
+    pub elapsed: Duration,
+    /// Estimated remaining time
+    pub eta: Option<Duration>,
+}
+
+impl Progress {
+    /// Done in 0 -> 1
+    pub fn fraction(&self) -> f32 {
+        match self.total {
+            0 => 1.0,
+            total => self.done as f32 / total as f32
+        }
+    }
+}
+
+/// Cancels running renders, clones share the same state
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    /// Create new token
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    /// Request cancellation
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// Was cancellation requested
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Error out if cancelled
+    pub fn check(&self) -> Result<(), Cancelled> {
+        match self.is_cancelled() {
+            true => Err(Cancelled),
+            false => Ok(())
+        }
+    }
+}
+
+/// Error returned from cancelled operations
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Progress callback & cancellation token
+#[derive(Clone, Default)]
+pub struct RenderHooks {
+    callback: Option<Arc<dyn Fn(Progress) + Send + Sync>>,
+    pub cancel: CancellationToken,
+}
+
+impl RenderHooks {
+    /// Create hooks without callback
+    pub fn new() -> RenderHooks {
+        RenderHooks::default()
+    }
+
+    /// Set progress callback
+    pub fn on_progress(mut self, callback: impl Fn(Progress) + Send + Sync + 'static) -> Self {
+        self.callback = Some(Arc::new(callback));
+        self
+    }
+
+    /// Set cancellation token
+    pub fn cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancel = token;
+        self
+    }
+
+    /// Start tracking progress of stage
+    pub fn tracker(&self, stage: Stage, total: usize) -> ProgressTracker {
+        ProgressTracker { stage, total, start: Instant::now(), callback: self.callback.clone() }
+    }
+}
+
+impl fmt::Debug for RenderHooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RenderHooks")
+            .field("callback", &self.callback.is_some())
+            .field("cancel", &self.cancel)
+            .finish()
+    }
+}
+
+/// Reports progress of a single stage
+pub struct ProgressTracker {
+    stage: Stage,
+    total: usize,
+    start: Instant,
+    callback: Option<Arc<dyn Fn(Progress) + Send + Sync>>,
+}
+
+impl ProgressTracker {
+    /// Report amount of done units
+    pub fn report(&self, done: usize) {
+        let callback = match &self.callback {
+            Some(callback) => callback,
+            None => return
+        };
+        let elapsed = self.start.elapsed();
+        let eta = match done {
+            0 => None,
+            done => Some(elapsed.mul_f64(self.total.saturating_sub(done) as f64 / done as f64))
+        };
+        callback(Progress { stage: self.stage, done, total: self.total, elapsed, eta });
+    }
+}
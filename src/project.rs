@@ -0,0 +1,456 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use anyhow::Error;
+use image::io::Reader as ImageReader;
+use serde::{Serialize, Deserialize, Deserializer};
+
+use crate::blend::BlendMode;
+use crate::editor::{Editor, EditorMeta, EndBehavior, Layer, LayerData, Sidechain, Transform, Effect};
+use crate::keyframe::Keyframes;
+use crate::source::FileLoader;
+use crate::text::{TextLayer, TextStyle};
+use crate::stretch::SpeedMode;
+use crate::transition::Transition;
+use crate::time::Rational;
+
+/// Serializable description of an `Editor`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Project {
+    pub meta: EditorMeta,
+    #[serde(default)]
+    pub layers: Vec<ProjectLayer>,
+    #[serde(default)]
+    pub transitions: Vec<ProjectTransition>,
+    /// File the project was loaded from, relative paths in it are resolved against it
+    #[serde(skip)]
+    path: Option<PathBuf>,
+}
+
+/// Transition from layer at index into the next one
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProjectTransition {
+    pub after: usize,
+    pub transition: Transition,
+}
+
+impl Project {
+    /// Create new empty project
+    pub fn new(width: u32, height: u32, duration: Duration, fps: impl Into<Rational>) -> Project {
+        Project { meta: EditorMeta::new(width, height, duration, fps), layers: vec![], transitions: vec![], path: None }
+    }
+
+    /// Add new layer
+    pub fn layer(mut self, layer: ProjectLayer) -> Self {
+        self.layers.push(layer);
+        self
+    }
+
+    /// Add transition between the last added layer and the next one
+    pub fn transition(mut self, transition: Transition) -> Self {
+        let after = self.layers.len().checked_sub(1).expect("Transition has to follow a layer");
+        self.transitions.push(ProjectTransition { after, transition });
+        self
+    }
+
+    /// Parse project from JSON
+    pub fn from_json(data: &str) -> Result<Project, Error> {
+        Ok(serde_json::from_str(data)?)
+    }
+
+    /// Serialize project to JSON
+    pub fn to_json(&self) -> Result<String, Error> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// Load project from JSON file
+    pub fn load(path: impl AsRef<Path>) -> Result<Project, Error> {
+        let mut project = Project::from_json(&std::fs::read_to_string(&path)?)?;
+        project.path = Some(std::fs::canonicalize(path)?);
+        Ok(project)
+    }
+
+    /// Save project as JSON file
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        std::fs::write(path, self.to_json()?)?;
+        Ok(())
+    }
+
+    /// Load all sources and build the editor
+    pub fn editor(&self, loader: &FileLoader) -> Result<Editor, Error> {
+        self.build(loader, &mut vec![])
+    }
+
+    /// Build the editor, parents = files of the projects this one is nested in
+    fn build(&self, loader: &FileLoader, parents: &mut Vec<PathBuf>) -> Result<Editor, Error> {
+        if let Some(path) = &self.path {
+            if parents.contains(path) {
+                return Err(anyhow!("Project is nested in itself: {path:?}"));
+            }
+            parents.push(path.clone());
+        }
+        let mut editor = Editor::with_meta(self.meta.clone());
+        let base = self.path.as_ref().and_then(|p| p.parent());
+        for layer in &self.layers {
+            editor = editor.layer(layer.build(loader, base, parents)?);
+        }
+        if self.path.is_some() {
+            parents.pop();
+        }
+        for t in &self.transitions {
+            editor = editor.transition_after(t.after, t.transition);
+        }
+        Ok(editor)
+    }
+}
+
+/// Where does the layer data come from
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum LayerSource {
+    /// Path to the source file (relative to the project)
+    File(PathBuf),
+    /// Rendered text
+    Text { text: String, style: TextStyle },
+    /// Another project file (relative to this one), rendered with transparent background
+    Nested { project: PathBuf },
+    /// Applies the effects to the layers below, `{ "adjustment": true }`
+    Adjustment { #[serde(deserialize_with = "only_true")] adjustment: bool },
+}
+
+/// Marker which can't be false
+fn only_true<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    match bool::deserialize(deserializer)? {
+        true => Ok(true),
+        false => Err(serde::de::Error::custom("adjustment can only be true"))
+    }
+}
+
+/// Serializable description of a `Layer`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProjectLayer {
+    pub source: LayerSource,
+    pub offset: Duration,
+    pub transform: Transform,
+    /// Position in the source to start playing from
+    #[serde(default, skip_serializing_if = "Duration::is_zero")]
+    pub source_in: Duration,
+    /// Position in the source to stop playing at, None = end of the source
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_out: Option<Duration>,
+    /// Grayscale image limiting the layer, white = visible
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mask: Option<PathBuf>,
+    /// Behavior when the duration is longer than the source
+    #[serde(default)]
+    pub end: EndBehavior,
+    /// Layer time -> source time curve
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time_remap: Option<Keyframes<Duration>>,
+    /// None = duration of the source
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration: Option<Duration>,
+    #[serde(default = "default_one")]
+    pub speed: f32,
+    /// Whether the audio changes pitch with speed
+    #[serde(default)]
+    pub speed_mode: SpeedMode,
+    /// Role of the audio in sidechain ducking
+    #[serde(default)]
+    pub sidechain: Sidechain,
+    #[serde(default = "default_one")]
+    pub opacity: f32,
+    #[serde(default)]
+    pub blend: BlendMode,
+    #[serde(default)]
+    pub effects: Vec<Effect>,
+}
+
+fn default_one() -> f32 {
+    1.0
+}
+
+impl ProjectLayer {
+    /// Create new layer from source file
+    pub fn new(source: impl AsRef<Path>, offset: Duration, transform: Transform) -> ProjectLayer {
+        ProjectLayer::with_source(LayerSource::File(source.as_ref().to_owned()), offset, transform)
+    }
+
+    /// Create new text layer
+    pub fn text(text: &str, style: TextStyle, offset: Duration, transform: Transform) -> ProjectLayer {
+        ProjectLayer::with_source(LayerSource::Text { text: text.to_string(), style }, offset, transform)
+    }
+
+    /// Create new layer from any source
+    pub fn with_source(source: LayerSource, offset: Duration, transform: Transform) -> ProjectLayer {
+        ProjectLayer {
+            source,
+            offset,
+            transform,
+            source_in: Duration::ZERO,
+            source_out: None,
+            mask: None,
+            end: EndBehavior::default(),
+            time_remap: None,
+            duration: None,
+            speed: 1.0,
+            speed_mode: SpeedMode::default(),
+            sidechain: Sidechain::default(),
+            opacity: 1.0,
+            blend: BlendMode::Normal,
+            effects: vec![]
+        }
+    }
+
+    /// Add new effect
+    pub fn effect(mut self, effect: Effect) -> Self {
+        self.effects.push(effect);
+        self
+    }
+
+    /// Set new duration of this layer
+    pub fn duration(mut self, duration: Duration) -> Self {
+        self.duration = Some(duration);
+        self
+    }
+
+    /// Play only the part of the source between in and out points, None = until the end
+    pub fn trim(mut self, source_in: Duration, source_out: Option<Duration>) -> Self {
+        self.source_in = source_in;
+        self.source_out = source_out;
+        self
+    }
+
+    /// Create new adjustment layer
+    pub fn adjustment(offset: Duration, duration: Duration) -> ProjectLayer {
+        ProjectLayer::with_source(LayerSource::Adjustment { adjustment: true }, offset, Transform::ZERO).duration(duration)
+    }
+
+    /// Limit the layer by grayscale image, white = visible
+    pub fn mask(mut self, mask: impl AsRef<Path>) -> Self {
+        self.mask = Some(mask.as_ref().to_owned());
+        self
+    }
+
+    /// What to do when the layer is longer than its source
+    pub fn end_behavior(mut self, end: EndBehavior) -> Self {
+        self.end = end;
+        self
+    }
+
+    /// Map layer time to source time, for reverse, ramps & freeze frames
+    pub fn time_remap(mut self, remap: Keyframes<Duration>) -> Self {
+        self.time_remap = Some(remap);
+        self
+    }
+
+    /// Change the speed of this layer
+    pub fn speed(mut self, speed: f32) -> Self {
+        self.speed = speed;
+        self
+    }
+
+    /// Set whether the audio changes pitch with speed
+    pub fn speed_mode(mut self, mode: SpeedMode) -> Self {
+        self.speed_mode = mode;
+        self
+    }
+
+    /// Set role of the audio in sidechain ducking
+    pub fn sidechain(mut self, sidechain: Sidechain) -> Self {
+        self.sidechain = sidechain;
+        self
+    }
+
+    /// Set opacity of this layer (0 -> 1)
+    pub fn opacity(mut self, opacity: f32) -> Self {
+        self.opacity = opacity;
+        self
+    }
+
+    /// Set blend mode of this layer
+    pub fn blend(mut self, blend: BlendMode) -> Self {
+        self.blend = blend;
+        self
+    }
+
+    /// Load the source and build the layer, relative paths are resolved against the working directory
+    pub fn layer(&self, loader: &FileLoader) -> Result<Layer, Error> {
+        self.build(loader, None, &mut vec![])
+    }
+
+    /// Build the layer, relative paths are resolved against base
+    fn build(&self, loader: &FileLoader, base: Option<&Path>, parents: &mut Vec<PathBuf>) -> Result<Layer, Error> {
+        let layer = match &self.source {
+            LayerSource::Adjustment { .. } => Layer::adjustment(self.offset, self.duration.unwrap_or(Duration::from_secs(5))),
+            _ => Layer::new(self.data(loader, base, parents)?, self.offset, self.transform)
+        };
+        let mut layer = layer
+            .speed(self.speed)
+            .speed_mode(self.speed_mode)
+            .sidechain(self.sidechain)
+            .opacity(self.opacity)
+            .blend(self.blend)
+            .end_behavior(self.end);
+        layer = match self.source_out {
+            Some(source_out) => layer.trim(self.source_in, source_out),
+            None => layer.trim_start(self.source_in)
+        };
+        if let Some(remap) = &self.time_remap {
+            layer = layer.time_remap(remap.clone());
+        }
+        if let Some(duration) = self.duration {
+            layer = layer.duration(duration);
+        }
+        if let Some(mask) = &self.mask {
+            layer = layer.mask(&ImageReader::open(resolve(base, mask))?.decode()?);
+        }
+        for effect in &self.effects {
+            layer = layer.effect(effect.clone());
+        }
+        Ok(layer)
+    }
+
+    /// Load the layer data of source
+    fn data(&self, loader: &FileLoader, base: Option<&Path>, parents: &mut Vec<PathBuf>) -> Result<Box<dyn LayerData + Send + Sync>, Error> {
+        Ok(match &self.source {
+            LayerSource::File(path) => {
+                let source = loader.load_file(resolve(base, path))?;
+                // Images take the duration of the layer
+                match self.duration {
+                    Some(duration) => source.layer_data_with_image_duration(duration)?,
+                    None => source.layer_data()?
+                }
+            },
+            LayerSource::Text { text, style } => {
+                Box::new(TextLayer::new(text, style, self.duration.unwrap_or(Duration::from_secs(5)))?)
+            },
+            LayerSource::Nested { project } => {
+                Box::new(Project::load(resolve(base, project))?.build(loader, parents)?.transparent(true))
+            },
+            LayerSource::Adjustment { .. } => return Err(anyhow!("Adjustment layer has no data"))
+        })
+    }
+}
+
+/// Path relative to base, absolute paths are kept
+fn resolve(base: Option<&Path>, path: &Path) -> PathBuf {
+    match base {
+        Some(base) => base.join(path),
+        None => path.to_path_buf()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GrayImage, Luma, Rgba, RgbaImage};
+    use crate::editor::Renderer;
+    use crate::ffmpeg::FFmpeg;
+    use crate::keyframe::Easing;
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("videoeditor-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn render(project: &Project, loader: &FileLoader, frame: usize) -> Vec<u8> {
+        let renderer = Renderer::new(project.editor(loader).unwrap());
+        renderer.render_frame(frame).unwrap().unwrap().into_bytes()
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let dir = temp_dir("project");
+        let image = dir.join("image.png");
+        RgbaImage::from_fn(32, 24, |x, y| Rgba([x as u8 * 8, y as u8 * 10, 128, 255])).save(&image).unwrap();
+        let loader = FileLoader::new(dir.join("cache"), FFmpeg::default());
+        // Absolute path, the project built in code has no file to be relative to
+        let nested = dir.join("nested.json");
+        Project::new(32, 24, Duration::from_secs(2), 30u32)
+            .layer(ProjectLayer::new(&image, Duration::ZERO, Transform::ZERO)
+                .duration(Duration::from_secs(2))
+                .effect(Effect::Rotate { angle: 0.5, uncropped: false }))
+            .save(&nested).unwrap();
+
+        let opacity = Keyframes::new()
+            .key(Duration::ZERO, 0.2, Easing::EaseIn)
+            .key(Duration::from_millis(800), 1.0, Easing::Linear);
+        let position = Keyframes::new()
+            .key(Duration::ZERO, (0.0, 0.0), Easing::CubicBezier(0.3, 0.0, 0.2, 1.0))
+            .key(Duration::from_secs(1), (20.0, 10.0), Easing::Linear);
+        let project = Project::new(64, 48, Duration::from_secs(2), 30u32)
+            .layer(ProjectLayer::new(&image, Duration::ZERO, Transform::ZERO)
+                .duration(Duration::from_secs(2))
+                .effect(Effect::Scale { x: 2.0, y: 2.0 }))
+            .layer(ProjectLayer::new(&image, Duration::from_millis(500), Transform::Px(16, 8))
+                .duration(Duration::from_secs(1))
+                .effect(Effect::FadeIn { duration: Duration::from_millis(300), easing: Easing::EaseOut })
+                .effect(Effect::OpacityKeyframes { opacity })
+                .opacity(0.7))
+            .layer(ProjectLayer::with_source(LayerSource::Nested { project: nested }, Duration::from_millis(200), Transform::Px(4, 4))
+                .effect(Effect::MoveKeyframes { position }))
+            .layer(ProjectLayer::adjustment(Duration::from_secs(1), Duration::from_secs(1))
+                .effect(Effect::ColorAdjust { brightness: 10, contrast: 1.2, saturation: 0.5, hue: 30 }));
+        let path = dir.join("project.json");
+        project.save(&path).unwrap();
+        let loaded = Project::load(&path).unwrap();
+        assert_eq!(loaded.to_json().unwrap(), project.to_json().unwrap());
+
+        // Later layers are visible, so the renders aren't trivially equal
+        assert_ne!(render(&project, &loader, 0), render(&project, &loader, 20));
+        for frame in [0, 10, 20, 35, 45] {
+            assert_eq!(render(&project, &loader, frame), render(&loaded, &loader, frame), "frame {frame}");
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn untagged_sources() {
+        // Text needs a font to render, so only the parsing is checked
+        let style = TextStyle::caption("font.ttf", 48.0).max_width(600);
+        let text = ProjectLayer::text("TOP TEXT", style.clone(), Duration::ZERO, Transform::ZERO);
+        let json = serde_json::to_string(&text).unwrap();
+        let parsed: ProjectLayer = serde_json::from_str(&json).unwrap();
+        assert!(matches!(parsed.source, LayerSource::Text { text, style: s } if text == "TOP TEXT" && s == style));
+
+        // Hand-written, with style defaults
+        let layers: Vec<ProjectLayer> = serde_json::from_str(r#"[
+            {"source": "clip.mp4", "offset": {"secs": 0, "nanos": 0}, "transform": {"Px": [0, 0]}},
+            {"source": {"text": "Hi", "style": {"font": "font.ttf", "size": 20.0, "color": [255, 0, 0, 255]}},
+                "offset": {"secs": 1, "nanos": 0}, "transform": {"Px": [0, 0]}},
+            {"source": {"project": "intro.json"}, "offset": {"secs": 2, "nanos": 0}, "transform": {"Px": [0, 0]}},
+            {"source": {"adjustment": true}, "offset": {"secs": 3, "nanos": 0}, "transform": {"Px": [0, 0]}}
+        ]"#).unwrap();
+        assert!(matches!(&layers[0].source, LayerSource::File(path) if path == Path::new("clip.mp4")));
+        assert!(matches!(&layers[1].source, LayerSource::Text { style, .. } if *style == TextStyle::new("font.ttf", 20.0).color([255, 0, 0, 255])));
+        assert!(matches!(&layers[2].source, LayerSource::Nested { project } if project == Path::new("intro.json")));
+        assert!(matches!(&layers[3].source, LayerSource::Adjustment { adjustment: true }));
+    }
+
+    #[test]
+    fn nested_relative_paths() {
+        // Sequence with its media in a subdirectory, not in the working directory
+        let dir = temp_dir("nested");
+        std::fs::create_dir_all(dir.join("sequence")).unwrap();
+        RgbaImage::from_fn(32, 24, |x, y| Rgba([x as u8 * 8, y as u8 * 10, 128, 255])).save(dir.join("sequence/image.png")).unwrap();
+        GrayImage::from_fn(32, 24, |x, _| Luma([if x < 16 { 255 } else { 0 }])).save(dir.join("sequence/mask.png")).unwrap();
+        Project::new(32, 24, Duration::from_secs(1), 30u32)
+            .layer(ProjectLayer::new("image.png", Duration::ZERO, Transform::ZERO)
+                .duration(Duration::from_secs(1))
+                .mask("mask.png"))
+            .save(dir.join("sequence/sequence.json")).unwrap();
+        let nested = LayerSource::Nested { project: "sequence/sequence.json".into() };
+        Project::new(64, 48, Duration::from_secs(1), 30u32)
+            .layer(ProjectLayer::with_source(nested, Duration::ZERO, Transform::ZERO))
+            .save(dir.join("main.json")).unwrap();
+
+        let loader = FileLoader::new(dir.join("cache"), FFmpeg::default());
+        let project = Project::load(dir.join("main.json")).unwrap();
+        let frame = Renderer::new(project.editor(&loader).unwrap()).render_frame(0).unwrap().unwrap().to_rgb8();
+        assert_eq!(frame.get_pixel(1, 1).0, [8, 10, 128]);
+        // Masked out
+        assert_eq!(frame.get_pixel(20, 1).0, [0, 0, 0]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
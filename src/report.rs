@@ -0,0 +1,108 @@
+use std::fmt;
+use anyhow::Error;
+
+/// How are per-frame errors handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ErrorMode {
+    /// Fail the render with all failed frames
+    #[default]
+    Strict,
+    /// Skip failing layers and collect the errors into `RenderReport`
+    Lenient,
+}
+
+/// Layer failed to render
+#[derive(Debug)]
+pub struct LayerError {
+    /// Index of the layer in the editor
+    pub layer: usize,
+    pub error: Error,
+}
+
+impl fmt::Display for LayerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Layer {}: {:#}", self.layer, self.error)
+    }
+}
+
+impl std::error::Error for LayerError {}
+
+/// Frame failed to render
+#[derive(Debug)]
+pub struct FrameError {
+    pub frame: usize,
+    /// None = the failure isn't caused by a layer (saving, encoding...)
+    pub layer: Option<usize>,
+    pub error: Error,
+}
+
+impl FrameError {
+    /// Wrap error of frame, extracting the layer if available
+    pub(crate) fn new(frame: usize, error: Error) -> FrameError {
+        match error.downcast::<LayerError>() {
+            Ok(e) => FrameError { frame, layer: Some(e.layer), error: e.error },
+            Err(error) => FrameError { frame, layer: None, error }
+        }
+    }
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.layer {
+            Some(layer) => write!(f, "Frame {} (layer {layer}): {:#}", self.frame, self.error),
+            None => write!(f, "Frame {}: {:#}", self.frame, self.error),
+        }
+    }
+}
+
+/// Result of a finished render
+#[derive(Debug, Default)]
+pub struct RenderReport {
+    /// Amount of rendered frames
+    pub frames: usize,
+    /// Failures skipped in lenient mode
+    pub failed: Vec<FrameError>,
+}
+
+impl RenderReport {
+    /// No failures
+    pub fn is_ok(&self) -> bool {
+        self.failed.is_empty()
+    }
+
+    /// Add result of rendered frame, skipped layer errors or failure of the whole frame
+    pub(crate) fn add(&mut self, frame: usize, result: Result<Vec<LayerError>, Error>) {
+        self.frames += 1;
+        match result {
+            Ok(errors) => self.failed.extend(errors.into_iter().map(|e| FrameError { frame, layer: Some(e.layer), error: e.error })),
+            Err(error) => self.failed.push(FrameError::new(frame, error)),
+        }
+    }
+
+    /// Apply the error mode, strict mode fails if anything failed
+    pub(crate) fn finish(mut self, mode: ErrorMode) -> Result<RenderReport, Error> {
+        self.failed.sort_by_key(|f| f.frame);
+        if mode == ErrorMode::Strict && !self.failed.is_empty() {
+            return Err(RenderError { failed: self.failed }.into());
+        }
+        Ok(self)
+    }
+}
+
+/// Render failed in strict mode
+#[derive(Debug)]
+pub struct RenderError {
+    pub failed: Vec<FrameError>,
+}
+
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} frame(s) failed to render", self.failed.len())?;
+        for failure in &self.failed {
+            write!(f, "\n{failure}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for RenderError {}
@@ -1,7 +1,9 @@
 use std::ffi::OsStr;
 use std::fs::File;
 use std::io::BufReader;
+use std::collections::VecDeque;
 use std::path::{PathBuf, Path};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use anyhow::Error;
 use image::DynamicImage;
@@ -11,18 +13,33 @@ use serde::{Serialize, Deserialize};
 use image::io::Reader as ImageReader;
 
 use crate::editor::{LayerData, AudioData};
-use crate::ffmpeg::FFmpeg;
+use crate::cache::{CacheKey, VideoCache};
+use crate::ffmpeg::{FFmpeg, FrameDecoder};
+use crate::probe::MediaProbe;
+use crate::time::{Rational, Timestamp};
 
 /// Loads and decodes files
 pub struct FileLoader {
-    video_cache_path: PathBuf,
-    ffmpeg: FFmpeg
+    cache: VideoCache,
+    ffmpeg: FFmpeg,
+    streaming: bool,
 }
 
 impl FileLoader {
     /// Create new instance
     pub fn new(video_cache_path: impl AsRef<Path>, ffmpeg: FFmpeg) -> FileLoader {
-        FileLoader { video_cache_path: video_cache_path.as_ref().to_owned(), ffmpeg }
+        FileLoader { cache: VideoCache::new(video_cache_path), ffmpeg, streaming: false }
+    }
+
+    /// Get the video cache manager
+    pub fn cache(&self) -> &VideoCache {
+        &self.cache
+    }
+
+    /// Decode videos on demand instead of caching all frames
+    pub fn streaming(mut self, streaming: bool) -> Self {
+        self.streaming = streaming;
+        self
     }
 
     /// Load file from path by extension
@@ -35,12 +52,14 @@ impl FileLoader {
             "mp3" | "wav" | "ogg" | "flac" => {
                 Ok(MediaSource::Audio(Audio::new(path)?))
             },
+            "mp4" | "mov" | "wmv" | "avi" | "webm" | "gif" | "mkv" | "m4v" if self.streaming => {
+                Ok(MediaSource::StreamingVideo(StreamingVideo::new(path, &self.ffmpeg)?))
+            },
             "mp4" | "mov" | "wmv" | "avi" | "webm" | "gif" | "mkv" | "m4v" => {
-                let filename = path.as_ref().file_name().unwrap().to_string_lossy();
-                let filename = filename.split(".").next().unwrap().to_owned();
+                let key = CacheKey::new(&path)?;
                 Ok(MediaSource::Video(Video::load_or_cache(
                     path, 
-                    self.video_cache_path.join(filename), 
+                    self.cache.entry_path(&key), 
                     &self.ffmpeg, 
                     [], 
                     []
@@ -55,6 +74,7 @@ impl FileLoader {
 #[derive(Debug, Clone)]
 pub enum MediaSource {
     Video(Video),
+    StreamingVideo(StreamingVideo),
     Audio(Audio),
     Image(Image)
 }
@@ -63,9 +83,10 @@ impl MediaSource {
     /// Get layer data
     pub fn layer_data_with_image_duration(self, duration: Duration) -> Result<Box<dyn LayerData + Send + Sync>, Error> {
         match self {
-            MediaSource::Video(v) => return Ok(Box::new(VideoLayer::new(v))),
-            MediaSource::Audio(a) => return Ok(Box::new(AudioLayer::new(a))),
-            MediaSource::Image(i) => return Ok(Box::new(ImageLayer::new(&i, duration)?)),
+            MediaSource::Video(v) => Ok(Box::new(VideoLayer::new(v))),
+            MediaSource::StreamingVideo(v) => Ok(Box::new(StreamingVideoLayer::new(v))),
+            MediaSource::Audio(a) => Ok(Box::new(AudioLayer::new(a))),
+            MediaSource::Image(i) => Ok(Box::new(ImageLayer::new(&i, duration)?)),
         }
     }
 
@@ -78,10 +99,43 @@ impl MediaSource {
 /// Metadata of video
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct VideoMeta {
+    /// Display size, after rotation & with square pixels
     pub width: u32,
     pub height: u32,
     pub duration: Duration,
-    pub frames: usize
+    pub frames: usize,
+    pub fps: Rational,
+}
+
+impl VideoMeta {
+    /// Metadata of the first video stream
+    pub fn from_probe(probe: &MediaProbe) -> Result<VideoMeta, Error> {
+        let video = probe.video().ok_or(anyhow!("Missing video stream"))?;
+        let duration = video.duration.or(probe.duration()).ok_or(anyhow!("Missing duration"))?;
+        // Stretch anamorphic pixels to square ones
+        let mut width = match video.sample_aspect_ratio {
+            Some(sar) if sar.num != sar.den => ((video.width as f64 * sar.as_f64()).round() as u32).max(1),
+            _ => video.width
+        };
+        let mut height = video.height;
+        // ffmpeg autorotates while decoding
+        if video.rotation.rem_euclid(180) != 0 {
+            (width, height) = (height, width);
+        }
+        Ok(VideoMeta {
+            width,
+            height,
+            duration,
+            frames: video.frames.unwrap_or((duration.as_secs_f64() * video.frame_rate.as_f64()).round() as usize),
+            fps: video.frame_rate,
+        })
+    }
+
+    /// Index of frame shown at position
+    pub fn frame_index(&self, offset: Duration) -> usize {
+        let index = Timestamp::from(offset).frame(self.fps) as usize;
+        index.min(self.frames.saturating_sub(1))
+    }
 }
 
 /// Video source
@@ -90,10 +144,13 @@ pub struct Video {
     pub path: PathBuf, 
     pub audio: bool,
     pub meta: VideoMeta,
+    /// Source the cache was generated from
+    #[serde(default)]
+    pub key: Option<CacheKey>,
 }
 
 impl Video {
-    /// Cache the given video
+    /// Cache the given video, stale cache (source changed) is regenerated
     pub fn load_or_cache<'a, A, B>(
         input_path: impl AsRef<Path>, 
         cache_path: impl AsRef<Path>, 
@@ -106,32 +163,75 @@ impl Video {
         B: IntoIterator<Item = &'a OsStr>
     {
         let out_path = cache_path.as_ref();
-        let meta_path = out_path.join("meta.json");
-        // Check if exists
-        match std::fs::read_to_string(&meta_path) {
-            Ok(data) => {
-                return Ok(serde_json::from_str(&data)?);
-            },
-            // Cache
-            Err(_) => { info!("Caching video: {:?}", input_path.as_ref()) },
+        let key = CacheKey::new(&input_path)?;
+        if let Some(video) = Video::load_cached(out_path, &key)? {
+            return Ok(video);
+        }
+
+        // Only one producer at a time, others wait and then use its output
+        let lock = VideoCache::lock(out_path)?;
+        if let Some(video) = Video::load_cached(out_path, &key)? {
+            return Ok(video);
         }
-        
-        // Split
-        let meta = ffmpeg.video_meta(&input_path)?;
-        std::fs::create_dir_all(&out_path.join("frames"))?;
-        ffmpeg.convert(&input_path, out_path.join("frames").join("%06d.png"), ffmpeg_split_args)?;
-        let audio = ffmpeg.convert(&input_path.as_ref(), &out_path.join("audio.mp3"), ffmpeg_audio_args).is_ok();
-        // Generate meta
-        let video = Video {
-            meta,
-            audio,
-            path: out_path.to_owned(),
+        info!("Caching video: {:?}", input_path.as_ref());
+        VideoCache::clean_temp(out_path)?;
+
+        // Split into temporary directory
+        let temp_path = VideoCache::temp_path(out_path);
+        let cache = || -> Result<Video, Error> {
+            let probe = ffmpeg.probe(&input_path)?;
+            let meta = VideoMeta::from_probe(&probe)?;
+            std::fs::create_dir_all(temp_path.join("frames"))?;
+            ffmpeg.convert(&input_path, temp_path.join("frames").join("%06d.png"), ffmpeg_split_args)?;
+            let audio = probe.has_audio();
+            if audio {
+                ffmpeg.convert(input_path.as_ref(), temp_path.join("audio.mp3"), ffmpeg_audio_args)?;
+            }
+            // Generate meta
+            let video = Video {
+                meta,
+                audio,
+                path: out_path.to_owned(),
+                key: Some(key),
+            };
+            // Save meta
+            std::fs::write(temp_path.join(VideoCache::META), serde_json::to_string_pretty(&video)?)?;
+            Ok(video)
         };
-        // Save meta
-        std::fs::write(&meta_path, serde_json::to_string_pretty(&video)?)?;
+        let video = match cache() {
+            Ok(video) => video,
+            Err(e) => {
+                std::fs::remove_dir_all(&temp_path).ok();
+                return Err(e);
+            }
+        };
+
+        // Move into place, replacing stale entry
+        if out_path.exists() {
+            std::fs::remove_dir_all(out_path)?;
+        }
+        std::fs::rename(&temp_path, out_path)?;
+        // Closing the file releases the lock
+        std::mem::drop(lock);
         Ok(video)
     }
 
+    /// Load existing cache if it's complete and up to date
+    fn load_cached(path: &Path, key: &CacheKey) -> Result<Option<Video>, Error> {
+        let data = match std::fs::read_to_string(path.join(VideoCache::META)) {
+            Ok(data) => data,
+            Err(_) => return Ok(None)
+        };
+        // Entries from older versions might not parse
+        let video = serde_json::from_str::<Video>(&data).ok().filter(|v| v.key.as_ref() == Some(key));
+        let Some(video) = video else {
+            info!("Cache is stale: {path:?}");
+            return Ok(None);
+        };
+        VideoCache::touch(path)?;
+        Ok(Some(video))
+    }
+
     /// Load frame as image
     pub fn frame(&self, index: usize) -> Result<DynamicImage, Error> {
         let image = ImageReader::open(self.path.join("frames").join(format!("{:06}.png", index+1)))?.decode()?;
@@ -153,6 +253,34 @@ impl Video {
     }
 }
 
+/// Video source decoded on demand
+#[derive(Debug, Clone)]
+pub struct StreamingVideo {
+    pub path: PathBuf,
+    pub audio: bool,
+    pub meta: VideoMeta,
+    ffmpeg: FFmpeg,
+}
+
+impl StreamingVideo {
+    /// Probe the video
+    pub fn new(path: impl AsRef<Path>, ffmpeg: &FFmpeg) -> Result<StreamingVideo, Error> {
+        let probe = ffmpeg.probe(&path)?;
+        Ok(StreamingVideo {
+            path: path.as_ref().to_owned(),
+            audio: probe.has_audio(),
+            meta: VideoMeta::from_probe(&probe)?,
+            ffmpeg: ffmpeg.clone()
+        })
+    }
+
+    /// Position to seek to for frame, half a frame early so rounding can't skip it
+    fn seek_position(&self, index: usize) -> Duration {
+        let half_frame = Rational::new(self.meta.fps.den, self.meta.fps.num * 2);
+        Timestamp::new(index as i64 * 2 - 1, half_frame).to_duration()
+    }
+}
+
 /// Image source
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Image {
@@ -244,9 +372,7 @@ impl LayerData for VideoLayer {
     }
 
     fn frame(&self, offset: Duration) -> Result<Option<DynamicImage>, Error> {
-        let t = offset.as_secs_f32() / self.video.meta.duration.as_secs_f32();
-        let frame = (t * self.video.meta.frames as f32) as usize;
-        Ok(Some(self.video.frame(frame)?))
+        Ok(Some(self.video.frame(self.video.meta.frame_index(offset))?))
     }
 
     fn audio(&self) -> Result<Option<AudioData>, Error> {
@@ -257,6 +383,91 @@ impl LayerData for VideoLayer {
     }
 }
 
+/// Video layer which decodes frames on demand
+pub struct StreamingVideoLayer {
+    video: StreamingVideo,
+    window: usize,
+    state: Mutex<StreamingState>,
+}
+
+/// Running decoder & recently decoded frames
+#[derive(Default)]
+struct StreamingState {
+    /// Decoder & index of the frame it will output next
+    decoder: Option<(FrameDecoder, usize)>,
+    frames: VecDeque<(usize, Arc<DynamicImage>)>,
+}
+
+impl StreamingVideoLayer {
+    /// How far ahead can the decoder skip instead of seeking
+    const MAX_SKIP: usize = 64;
+
+    /// Create new video layer keeping 16 decoded frames in memory
+    pub fn new(video: StreamingVideo) -> StreamingVideoLayer {
+        StreamingVideoLayer { video, window: 16, state: Mutex::new(StreamingState::default()) }
+    }
+
+    /// Set how many recently decoded frames are kept in memory.
+    /// Should be at least twice the render thread count.
+    pub fn window(mut self, window: usize) -> Self {
+        self.window = window.max(1);
+        self
+    }
+
+    /// Get frame by index, decoding forward or seeking if needed
+    fn decode(&self, index: usize) -> Result<Option<Arc<DynamicImage>>, Error> {
+        let mut state = self.state.lock().map_err(|_| anyhow!("Decoder state poisoned"))?;
+        if let Some((_, frame)) = state.frames.iter().find(|(i, _)| *i == index) {
+            return Ok(Some(frame.clone()));
+        }
+        // Seek if the frame is behind or too far ahead
+        let reuse = matches!(&state.decoder, Some((_, next)) if *next <= index && index - *next <= Self::MAX_SKIP);
+        if !reuse {
+            let decoder = self.video.ffmpeg.decoder(&self.video.path, self.video.seek_position(index), self.video.meta.width, self.video.meta.height)?;
+            state.decoder = Some((decoder, index));
+        }
+        // Read until the requested frame
+        loop {
+            let (decoder, next) = state.decoder.as_mut().unwrap();
+            let current = *next;
+            let frame = match decoder.read_frame()? {
+                Some(frame) => Arc::new(DynamicImage::from(frame)),
+                None => {
+                    state.decoder = None;
+                    return Ok(None);
+                }
+            };
+            *next += 1;
+            state.frames.push_back((current, frame.clone()));
+            while state.frames.len() > self.window {
+                state.frames.pop_front();
+            }
+            if current == index {
+                return Ok(Some(frame));
+            }
+        }
+    }
+}
+
+impl LayerData for StreamingVideoLayer {
+    fn duration(&self) -> Duration {
+        self.video.meta.duration
+    }
+
+    fn frame(&self, offset: Duration) -> Result<Option<DynamicImage>, Error> {
+        let frame = self.decode(self.video.meta.frame_index(offset))?
+            .ok_or(anyhow!("Frame out of range: {offset:?}"))?;
+        Ok(Some(frame.as_ref().clone()))
+    }
+
+    fn audio(&self) -> Result<Option<AudioData>, Error> {
+        match self.video.audio {
+            true => Ok(Some(AudioData::new(self.video.ffmpeg.audio_stream(&self.video.path, 44100, 2)?))),
+            false => Ok(None)
+        }
+    }
+}
+
 /// Audio layer
 pub struct AudioLayer {
     audio: Audio
@@ -0,0 +1,106 @@
+use std::f32::consts::PI;
+use std::ops::RangeInclusive;
+use serde::{Serialize, Deserialize};
+
+use crate::progress::{Cancelled, CancellationToken};
+
+/// Length of the overlapped frames in seconds
+const FRAME: f64 = 0.04;
+/// Max shift of a frame when searching for the best overlap, in seconds
+const TOLERANCE: f64 = 0.01;
+/// Downsampling of the rough overlap search
+const DECIMATION: usize = 4;
+
+/// How is audio of a layer played at changed speed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum SpeedMode {
+    /// Faster = higher pitch, like a tape
+    #[default]
+    Resample,
+    /// Keep the pitch, change only the tempo
+    PreservePitch,
+}
+
+/// Change tempo of interleaved samples without changing the pitch (WSOLA), speed 2 = half the length
+pub fn time_stretch(samples: &[f32], channels: u16, sample_rate: u32, speed: f32, cancel: &CancellationToken) -> Result<Vec<f32>, Cancelled> {
+    let channels = channels.max(1) as usize;
+    let frames = samples.len() / channels;
+    if !speed.is_finite() || speed <= 0.0 || frames == 0 {
+        return Ok(samples.to_vec());
+    }
+    let length = (frames as f64 / speed as f64).round() as usize;
+    let size = ((sample_rate as f64 * FRAME) as usize).max(4) / 2 * 2;
+    let hop = size / 2;
+    let tolerance = (sample_rate as f64 * TOLERANCE) as isize;
+
+    // Similarity is searched on downmixed audio, first roughly on a decimated copy
+    let mono = samples.chunks_exact(channels).map(|f| f.iter().sum::<f32>() / channels as f32).collect::<Vec<_>>();
+    let coarse = mono.chunks(DECIMATION).map(|c| c.iter().sum::<f32>() / c.len() as f32).collect::<Vec<_>>();
+    // Periodic Hann, overlapping halves sum to 1
+    let window = (0..size).map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / size as f32).cos()).collect::<Vec<_>>();
+
+    let mut output = vec![0f32; (length + size) * channels];
+    let mut weights = vec![0f32; length + size];
+    let mut previous: Option<isize> = None;
+    for k in 0..length.div_ceil(hop) {
+        if k % 64 == 0 {
+            cancel.check()?;
+        }
+        let ideal = (k as f64 * hop as f64 * speed as f64) as isize;
+        // Continue where the previous frame would naturally go on
+        let start = match previous {
+            None => ideal,
+            Some(previous) => {
+                let target = previous + hop as isize;
+                let step = DECIMATION as isize;
+                let rough = best_offset(&coarse, target / step, ideal / step, -tolerance / step..=tolerance / step, size / DECIMATION, 2);
+                let rough = ideal + rough * step;
+                rough + best_offset(&mono, target, rough, -step..=step, size, 2)
+            }
+        };
+        for (i, w) in window.iter().enumerate() {
+            let out = k * hop + i;
+            for c in 0..channels {
+                output[out * channels + c] += read(samples, (start + i as isize) * channels as isize + c as isize) * w;
+            }
+            weights[out] += w;
+        }
+        previous = Some(start);
+    }
+
+    // Edges aren't fully overlapped
+    for (frame, weight) in output.chunks_exact_mut(channels).zip(weights) {
+        if weight > 1e-3 {
+            frame.iter_mut().for_each(|s| *s /= weight);
+        }
+    }
+    output.truncate(length * channels);
+    Ok(output)
+}
+
+/// Offset from start in range, whose window of size is the most similar to the one at target
+fn best_offset(signal: &[f32], target: isize, start: isize, range: RangeInclusive<isize>, size: usize, stride: usize) -> isize {
+    let mut best = (f32::MIN, 0);
+    for offset in range {
+        let candidate = start + offset;
+        let (mut correlation, mut energy) = (0.0, 0.0);
+        for i in (0..size as isize).step_by(stride) {
+            let value = read(signal, candidate + i);
+            correlation += read(signal, target + i) * value;
+            energy += value * value;
+        }
+        let score = correlation / energy.sqrt().max(1e-6);
+        if score > best.0 {
+            best = (score, offset);
+        }
+    }
+    best.1
+}
+
+/// Sample at index, zero outside the buffer
+fn read(buffer: &[f32], index: isize) -> f32 {
+    match index >= 0 {
+        true => buffer.get(index as usize).copied().unwrap_or(0.0),
+        false => 0.0
+    }
+}
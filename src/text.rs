@@ -0,0 +1,262 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use anyhow::Error;
+use image::{DynamicImage, Rgba, RgbaImage};
+use rusttype::{Font, Scale, point};
+use serde::{Serialize, Deserialize};
+
+use crate::editor::{LayerData, AudioData};
+
+/// Horizontal alignment of text lines
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub enum TextAlign {
+    #[default]
+    Left,
+    Center,
+    Right
+}
+
+/// Outline around the text
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Stroke {
+    /// Width in pixels
+    pub width: f32,
+    pub color: [u8; 4]
+}
+
+/// How should the text look
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TextStyle {
+    /// Path to TTF / OTF font
+    pub font: PathBuf,
+    /// Font size in pixels
+    pub size: f32,
+    pub color: [u8; 4],
+    #[serde(default)]
+    pub stroke: Option<Stroke>,
+    #[serde(default)]
+    pub align: TextAlign,
+    /// Wrap lines longer than this (in pixels)
+    #[serde(default)]
+    pub max_width: Option<u32>,
+    /// Multiplier of the font's line height
+    #[serde(default = "default_line_spacing")]
+    pub line_spacing: f32,
+}
+
+fn default_line_spacing() -> f32 {
+    1.0
+}
+
+impl TextStyle {
+    /// Create new style with white left aligned text
+    pub fn new(font: impl AsRef<Path>, size: f32) -> TextStyle {
+        TextStyle {
+            font: font.as_ref().to_owned(),
+            size,
+            color: [255, 255, 255, 255],
+            stroke: None,
+            align: TextAlign::Left,
+            max_width: None,
+            line_spacing: 1.0
+        }
+    }
+
+    /// Classic top / bottom meme caption (white, black outline, centered)
+    pub fn caption(font: impl AsRef<Path>, size: f32) -> TextStyle {
+        TextStyle::new(font, size)
+            .stroke(size / 16.0, [0, 0, 0, 255])
+            .align(TextAlign::Center)
+    }
+
+    /// Set fill color (RGBA)
+    pub fn color(mut self, color: [u8; 4]) -> Self {
+        self.color = color;
+        self
+    }
+
+    /// Add outline
+    pub fn stroke(mut self, width: f32, color: [u8; 4]) -> Self {
+        self.stroke = Some(Stroke { width, color });
+        self
+    }
+
+    /// Set alignment
+    pub fn align(mut self, align: TextAlign) -> Self {
+        self.align = align;
+        self
+    }
+
+    /// Wrap lines to max width in pixels
+    pub fn max_width(mut self, max_width: u32) -> Self {
+        self.max_width = Some(max_width);
+        self
+    }
+
+    /// Set line spacing multiplier
+    pub fn line_spacing(mut self, line_spacing: f32) -> Self {
+        self.line_spacing = line_spacing;
+        self
+    }
+
+    /// Load the font and render text into RGBA image
+    pub fn render(&self, text: &str) -> Result<DynamicImage, Error> {
+        let font = Font::try_from_vec(std::fs::read(&self.font)?).ok_or(anyhow!("Invalid font: {:?}", self.font))?;
+        Ok(self.render_with_font(&font, text))
+    }
+
+    /// Render text with already loaded font
+    pub fn render_with_font(&self, font: &Font, text: &str) -> DynamicImage {
+        let scale = Scale::uniform(self.size);
+        let v_metrics = font.v_metrics(scale);
+        let line_height = (v_metrics.ascent - v_metrics.descent + v_metrics.line_gap) * self.line_spacing;
+        let lines = self.wrap(font, scale, text);
+        let widths = lines.iter().map(|l| text_width(font, scale, l)).collect::<Vec<_>>();
+
+        // Canvas size with room for the outline
+        let pad = self.stroke.map(|s| s.width.ceil() as u32).unwrap_or(0) + 1;
+        let text_width = widths.iter().cloned().fold(0.0f32, f32::max).ceil() as u32;
+        let text_height = (line_height * (lines.len().max(1) - 1) as f32 + v_metrics.ascent - v_metrics.descent).ceil() as u32;
+        let (width, height) = (text_width + pad * 2, text_height + pad * 2);
+
+        // Rasterize glyph coverage
+        let mut mask = vec![0.0f32; width as usize * height as usize];
+        for (i, (line, line_width)) in lines.iter().zip(&widths).enumerate() {
+            let x = pad as f32 + match self.align {
+                TextAlign::Left => 0.0,
+                TextAlign::Center => (text_width as f32 - line_width) / 2.0,
+                TextAlign::Right => text_width as f32 - line_width,
+            };
+            let y = pad as f32 + v_metrics.ascent + line_height * i as f32;
+            for glyph in font.layout(line, scale, point(x, y)) {
+                if let Some(bb) = glyph.pixel_bounding_box() {
+                    glyph.draw(|gx, gy, v| {
+                        let (px, py) = (gx as i32 + bb.min.x, gy as i32 + bb.min.y);
+                        if px >= 0 && py >= 0 && (px as u32) < width && (py as u32) < height {
+                            let i = py as usize * width as usize + px as usize;
+                            mask[i] = mask[i].max(v);
+                        }
+                    });
+                }
+            }
+        }
+
+        // Outline coverage
+        let outline = self.stroke.map(|s| (dilate(&mask, width, height, s.width), s.color));
+
+        // Compose
+        let mut image = RgbaImage::new(width, height);
+        for (i, pixel) in image.pixels_mut().enumerate() {
+            let mut out = [0.0f32; 4];
+            if let Some((outline, color)) = &outline {
+                out = over(out, *color, outline[i]);
+            }
+            out = over(out, self.color, mask[i]);
+            *pixel = Rgba([
+                (out[0] * 255.0) as u8,
+                (out[1] * 255.0) as u8,
+                (out[2] * 255.0) as u8,
+                (out[3] * 255.0) as u8
+            ]);
+        }
+        image.into()
+    }
+
+    /// Split text into lines fitting max width
+    fn wrap(&self, font: &Font, scale: Scale, text: &str) -> Vec<String> {
+        let mut lines = vec![];
+        for paragraph in text.lines() {
+            let max_width = match self.max_width {
+                Some(w) => w as f32,
+                None => {
+                    lines.push(paragraph.to_string());
+                    continue;
+                }
+            };
+            let mut line = String::new();
+            for word in paragraph.split_whitespace() {
+                let candidate = if line.is_empty() { word.to_string() } else { format!("{line} {word}") };
+                if !line.is_empty() && text_width(font, scale, &candidate) > max_width {
+                    lines.push(std::mem::replace(&mut line, word.to_string()));
+                } else {
+                    line = candidate;
+                }
+            }
+            lines.push(line);
+        }
+        lines
+    }
+}
+
+/// Width of single line in pixels
+fn text_width(font: &Font, scale: Scale, text: &str) -> f32 {
+    font.layout(text, scale, point(0.0, 0.0))
+        .last()
+        .map(|g| g.position().x + g.unpositioned().h_metrics().advance_width)
+        .unwrap_or(0.0)
+}
+
+/// Grow coverage mask by radius (antialiased)
+fn dilate(mask: &[f32], width: u32, height: u32, radius: f32) -> Vec<f32> {
+    let r = radius.ceil() as i32;
+    let (w, h) = (width as i32, height as i32);
+    let mut out = vec![0.0f32; mask.len()];
+    for y in 0..h {
+        for x in 0..w {
+            let mut coverage = 0.0f32;
+            for dy in -r..=r {
+                for dx in -r..=r {
+                    let (sx, sy) = (x + dx, y + dy);
+                    if sx < 0 || sy < 0 || sx >= w || sy >= h {
+                        continue;
+                    }
+                    let falloff = (radius + 0.5 - ((dx * dx + dy * dy) as f32).sqrt()).clamp(0.0, 1.0);
+                    coverage = coverage.max(mask[(sy * w + sx) as usize] * falloff);
+                }
+            }
+            out[(y * w + x) as usize] = coverage;
+        }
+    }
+    out
+}
+
+/// Alpha composite RGBA color with coverage over premultiplied-free float pixel
+fn over(dst: [f32; 4], color: [u8; 4], coverage: f32) -> [f32; 4] {
+    let a = color[3] as f32 / 255.0 * coverage;
+    if a <= 0.0 {
+        return dst;
+    }
+    let out_a = a + dst[3] * (1.0 - a);
+    let mut out = [0.0, 0.0, 0.0, out_a];
+    for c in 0..3 {
+        out[c] = (color[c] as f32 / 255.0 * a + dst[c] * dst[3] * (1.0 - a)) / out_a;
+    }
+    out
+}
+
+/// Text layer (captions)
+pub struct TextLayer {
+    image: DynamicImage,
+    duration: Duration
+}
+
+impl TextLayer {
+    /// Render text into new layer
+    pub fn new(text: &str, style: &TextStyle, duration: Duration) -> Result<TextLayer, Error> {
+        Ok(TextLayer { image: style.render(text)?, duration })
+    }
+}
+
+impl LayerData for TextLayer {
+    fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    fn frame(&self, _offset: Duration) -> Result<Option<DynamicImage>, Error> {
+        Ok(Some(self.image.clone()))
+    }
+
+    fn audio(&self) -> Result<Option<AudioData>, Error> {
+        Ok(None)
+    }
+}
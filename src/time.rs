@@ -0,0 +1,289 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::ops::{Add, Sub};
+use std::str::FromStr;
+use std::time::Duration;
+use anyhow::Error;
+use serde::{Serialize, Deserialize};
+
+const NANOS: i128 = 1_000_000_000;
+
+/// Exact fraction, used for frame rates & timebases
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "RationalRepr")]
+pub struct Rational {
+    pub num: i64,
+    pub den: i64,
+}
+
+impl Rational {
+    /// Create new fraction
+    pub fn new(num: i64, den: i64) -> Rational {
+        Rational { num, den }
+    }
+
+    /// Value as float
+    pub fn as_f64(&self) -> f64 {
+        self.num as f64 / self.den as f64
+    }
+
+    /// Non zero numerator and denominator
+    pub fn is_valid(&self) -> bool {
+        self.num != 0 && self.den != 0
+    }
+
+    /// Inverse fraction, fps -> timebase
+    pub fn recip(&self) -> Rational {
+        Rational::new(self.den, self.num)
+    }
+
+    /// Lowest terms with positive denominator
+    pub fn reduce(&self) -> Rational {
+        let g = gcd(self.num, self.den).max(1) * self.den.signum();
+        Rational::new(self.num / g, self.den / g)
+    }
+}
+
+/// Approximate float, NTSC rates (29.97, 23.976...) become x000/1001
+impl From<f64> for Rational {
+    fn from(value: f64) -> Self {
+        if value.fract() == 0.0 {
+            return Rational::new(value as i64, 1);
+        }
+        let ntsc = (value * 1.001).round();
+        if (ntsc / 1.001 - value).abs() < 0.005 {
+            return Rational::new(ntsc as i64 * 1000, 1001);
+        }
+        Rational::new((value * 1000.0).round() as i64, 1000).reduce()
+    }
+}
+
+impl From<f32> for Rational {
+    fn from(value: f32) -> Self {
+        Rational::from(value as f64)
+    }
+}
+
+impl From<u32> for Rational {
+    fn from(value: u32) -> Self {
+        Rational::new(value as i64, 1)
+    }
+}
+
+impl From<(i64, i64)> for Rational {
+    fn from((num, den): (i64, i64)) -> Self {
+        Rational::new(num, den)
+    }
+}
+
+impl fmt::Display for Rational {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.num, self.den)
+    }
+}
+
+/// Parse `num/den` or `num:den` (ffmpeg notation)
+impl FromStr for Rational {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (num, den) = s.split_once(['/', ':']).unwrap_or((s, "1"));
+        Ok(Rational { num: num.trim().parse()?, den: den.trim().parse()? })
+    }
+}
+
+/// Also accepts plain numbers & strings, so older projects with float fps still load
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RationalRepr {
+    Fraction { num: i64, den: i64 },
+    Number(f64),
+    Text(String),
+}
+
+impl TryFrom<RationalRepr> for Rational {
+    type Error = Error;
+
+    fn try_from(value: RationalRepr) -> Result<Self, Self::Error> {
+        let r = match value {
+            RationalRepr::Fraction { num, den } => Rational::new(num, den),
+            RationalRepr::Number(n) => Rational::from(n),
+            RationalRepr::Text(s) => s.parse()?,
+        };
+        if r.den == 0 {
+            return Err(anyhow!("Zero denominator: {r}"));
+        }
+        Ok(r)
+    }
+}
+
+/// Exact point in time, count of ticks (frames, samples) of a timebase in seconds
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Timestamp {
+    pub ticks: i64,
+    pub timebase: Rational,
+}
+
+impl Timestamp {
+    pub const ZERO: Timestamp = Timestamp { ticks: 0, timebase: Rational { num: 1, den: 1 } };
+
+    /// Create new timestamp
+    pub fn new(ticks: i64, timebase: Rational) -> Timestamp {
+        Timestamp { ticks, timebase }
+    }
+
+    /// Start of frame at frame rate
+    pub fn from_frame(index: usize, fps: Rational) -> Timestamp {
+        Timestamp::new(index as i64, fps.recip())
+    }
+
+    /// Position of sample (per channel) at sample rate
+    pub fn from_samples(sample: usize, sample_rate: u32) -> Timestamp {
+        Timestamp::new(sample as i64, Rational::new(1, sample_rate as i64))
+    }
+
+    /// Amount of whole ticks of timebase since zero, rounded down
+    pub fn ticks_in(&self, timebase: Rational) -> i64 {
+        let num = self.ticks as i128 * self.timebase.num as i128 * timebase.den as i128;
+        let den = self.timebase.den as i128 * timebase.num as i128;
+        num.div_euclid(den) as i64
+    }
+
+    /// Index of frame at frame rate, rounded down
+    pub fn frame(&self, fps: Rational) -> i64 {
+        self.ticks_in(fps.recip())
+    }
+
+    /// Convert into timebase, rounded down
+    pub fn rescale(&self, timebase: Rational) -> Timestamp {
+        Timestamp::new(self.ticks_in(timebase), timebase)
+    }
+
+    /// Convert into duration, negative = zero.
+    /// Rounded up to whole nanoseconds so converting back yields the same tick.
+    pub fn to_duration(&self) -> Duration {
+        let num = self.ticks as i128 * self.timebase.num as i128 * NANOS;
+        let den = self.timebase.den as i128;
+        let nanos = num.div_euclid(den) + (num.rem_euclid(den) != 0) as i128;
+        Duration::from_nanos(nanos.max(0) as u64)
+    }
+
+    /// Both timestamps in a shared timebase
+    fn common(&self, other: &Timestamp) -> (i128, i128, Rational) {
+        let (a, b) = (self.timebase.reduce(), other.timebase.reduce());
+        let num = gcd(a.num, b.num).max(1);
+        let den = a.den / gcd(a.den, b.den).max(1) * b.den;
+        let scale = |t: i64, r: Rational| t as i128 * (r.num / num) as i128 * (den / r.den) as i128;
+        (scale(self.ticks, a), scale(other.ticks, b), Rational::new(num, den))
+    }
+}
+
+impl From<Duration> for Timestamp {
+    fn from(value: Duration) -> Self {
+        Timestamp::new(value.as_nanos() as i64, Rational::new(1, NANOS as i64))
+    }
+}
+
+impl Add for Timestamp {
+    type Output = Timestamp;
+
+    fn add(self, rhs: Timestamp) -> Self::Output {
+        let (a, b, timebase) = self.common(&rhs);
+        Timestamp::new((a + b) as i64, timebase)
+    }
+}
+
+impl Sub for Timestamp {
+    type Output = Timestamp;
+
+    fn sub(self, rhs: Timestamp) -> Self::Output {
+        let (a, b, timebase) = self.common(&rhs);
+        Timestamp::new((a - b) as i64, timebase)
+    }
+}
+
+impl Add<Duration> for Timestamp {
+    type Output = Timestamp;
+
+    fn add(self, rhs: Duration) -> Self::Output {
+        self + Timestamp::from(rhs)
+    }
+}
+
+impl PartialEq for Timestamp {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Timestamp {}
+
+impl PartialOrd for Timestamp {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Timestamp {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let a = self.ticks as i128 * self.timebase.num as i128 * other.timebase.den as i128;
+        let b = other.ticks as i128 * other.timebase.num as i128 * self.timebase.den as i128;
+        a.cmp(&b)
+    }
+}
+
+/// Greatest common divisor
+fn gcd(a: i64, b: i64) -> i64 {
+    let (mut a, mut b) = (a.abs(), b.abs());
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NTSC: Rational = Rational { num: 30000, den: 1001 };
+
+    #[test]
+    fn frame_round_trip() {
+        // Over an hour at 29.97, float seconds would drift by frames
+        for index in (0..108_000).step_by(7).chain([107_892, 107_999]) {
+            let time = Timestamp::from_frame(index, NTSC);
+            assert_eq!(time.frame(NTSC), index as i64);
+            assert_eq!(Timestamp::from(time.to_duration()).frame(NTSC), index as i64);
+        }
+    }
+
+    #[test]
+    fn mixed_timebases() {
+        let frame = Timestamp::from_frame(1, NTSC);
+        let samples = Timestamp::from_samples(48048, 48000);
+        assert_eq!((frame + samples).to_duration(), Duration::from_nanos(1_034_366_667));
+        assert_eq!(frame + samples - samples, frame);
+        assert_eq!(samples - frame + frame, samples);
+        assert!(frame < samples);
+        assert_eq!(Timestamp::from_frame(30000, NTSC), Timestamp::new(1001, Rational::new(1, 1)));
+        assert_eq!((samples + Duration::from_millis(500)).ticks_in(Rational::new(1, 48000)), 72048);
+    }
+
+    #[test]
+    fn negative_clamps_to_zero() {
+        let time = Timestamp::from_frame(10, NTSC) - Timestamp::from(Duration::from_secs(1));
+        assert!(time < Timestamp::ZERO);
+        assert_eq!(time.to_duration(), Duration::ZERO);
+        assert_eq!(time.frame(NTSC), -20);
+    }
+
+    #[test]
+    fn float_rates() {
+        assert_eq!(Rational::from(29.97), NTSC);
+        assert_eq!(Rational::from(23.976), Rational::new(24000, 1001));
+        assert_eq!(Rational::from(59.94f32), Rational::new(60000, 1001));
+        assert_eq!(Rational::from(25.0), Rational::new(25, 1));
+        assert_eq!(Rational::from(12.5), Rational::new(25, 2));
+        assert_eq!("30000:1001".parse::<Rational>().unwrap(), NTSC);
+    }
+}
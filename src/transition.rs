@@ -0,0 +1,146 @@
+use std::f32::consts::PI;
+use std::time::Duration;
+use image::{DynamicImage, Rgba, RgbaImage};
+use image::imageops::overlay;
+use serde::{Serialize, Deserialize};
+
+/// Direction of movement
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Direction {
+    Left,
+    Right,
+    Up,
+    Down
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum TransitionKind {
+    Crossfade,
+    DipToBlack,
+    DipToWhite,
+    /// Reveal the incoming layer with an edge moving in direction
+    Wipe(Direction),
+    /// Incoming layer slides over the outgoing one
+    Slide(Direction),
+    /// Incoming layer pushes the outgoing one out
+    Push(Direction),
+    /// Outgoing layer zooms in while fading into the incoming one
+    Zoom,
+}
+
+/// Transition between two consecutive layers.
+/// Starts at the offset of the incoming layer and lasts for `duration`,
+/// the outgoing layer is hidden once it's done.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Transition {
+    pub kind: TransitionKind,
+    pub duration: Duration,
+    /// Crossfade audio of the layers as well
+    pub audio: bool,
+}
+
+impl Transition {
+    /// Create new transition with audio crossfade
+    pub fn new(kind: TransitionKind, duration: Duration) -> Transition {
+        Transition { kind, duration, audio: true }
+    }
+
+    /// Enable or disable audio crossfade
+    pub fn audio(mut self, audio: bool) -> Self {
+        self.audio = audio;
+        self
+    }
+
+    /// Progress 0 -> 1 of the transition at offset relative to its start, zero duration is a hard cut
+    pub(crate) fn progress(&self, offset: Duration) -> f32 {
+        if self.duration.is_zero() {
+            return 1.0;
+        }
+        (offset.as_secs_f32() / self.duration.as_secs_f32()).clamp(0.0, 1.0)
+    }
+
+    /// Equal power gains of (outgoing, incoming) audio at progress
+    pub(crate) fn audio_gains(&self, t: f32) -> (f32, f32) {
+        ((t * PI / 2.0).cos(), (t * PI / 2.0).sin())
+    }
+
+    /// Mix fully composited outgoing & incoming frames at progress 0 -> 1
+    pub(crate) fn mix(&self, from: &DynamicImage, to: &DynamicImage, t: f32) -> DynamicImage {
+        let (from, to) = (from.to_rgba8(), to.to_rgba8());
+        let (w, h) = (from.width(), from.height());
+        let output = match self.kind {
+            TransitionKind::Crossfade => blend(&from, &to, t),
+            TransitionKind::DipToBlack => dip(&from, &to, t, Rgba([0, 0, 0, 255])),
+            TransitionKind::DipToWhite => dip(&from, &to, t, Rgba([255, 255, 255, 255])),
+            TransitionKind::Wipe(direction) => {
+                let mut output = from;
+                for (x, y, pixel) in output.enumerate_pixels_mut() {
+                    let revealed = match direction {
+                        Direction::Right => (x as f32) < t * w as f32,
+                        Direction::Left => (x as f32) >= (1.0 - t) * w as f32,
+                        Direction::Down => (y as f32) < t * h as f32,
+                        Direction::Up => (y as f32) >= (1.0 - t) * h as f32,
+                    };
+                    if revealed {
+                        *pixel = *to.get_pixel(x, y);
+                    }
+                }
+                output
+            },
+            TransitionKind::Slide(direction) => {
+                let mut output = from;
+                let (x, y) = slide_offset(direction, w, h, t);
+                overlay(&mut output, &to, x, y);
+                output
+            },
+            TransitionKind::Push(direction) => {
+                let mut output = RgbaImage::new(w, h);
+                let (x, y) = slide_offset(direction, w, h, t);
+                let (dx, dy) = slide_offset(direction, w, h, 0.0);
+                overlay(&mut output, &from, x - dx, y - dy);
+                overlay(&mut output, &to, x, y);
+                output
+            },
+            TransitionKind::Zoom => {
+                let scale = 1.0 + t;
+                let (cx, cy) = (w as f32 / 2.0, h as f32 / 2.0);
+                let zoomed = RgbaImage::from_fn(w, h, |x, y| {
+                    let sx = (cx + (x as f32 - cx) / scale) as u32;
+                    let sy = (cy + (y as f32 - cy) / scale) as u32;
+                    *from.get_pixel(sx.min(w - 1), sy.min(h - 1))
+                });
+                blend(&zoomed, &to, t)
+            },
+        };
+        output.into()
+    }
+}
+
+/// Position of incoming layer sliding in direction
+fn slide_offset(direction: Direction, w: u32, h: u32, t: f32) -> (i64, i64) {
+    let (w, h) = (w as f32, h as f32);
+    let (x, y) = match direction {
+        Direction::Right => (-w + t * w, 0.0),
+        Direction::Left => (w - t * w, 0.0),
+        Direction::Down => (0.0, -h + t * h),
+        Direction::Up => (0.0, h - t * h),
+    };
+    (x.round() as i64, y.round() as i64)
+}
+
+/// Linear blend of two frames of the same size
+fn blend(from: &RgbaImage, to: &RgbaImage, t: f32) -> RgbaImage {
+    RgbaImage::from_fn(from.width(), from.height(), |x, y| {
+        let (a, b) = (from.get_pixel(x, y), to.get_pixel(x, y));
+        Rgba([0, 1, 2, 3].map(|c| (a[c] as f32 + (b[c] as f32 - a[c] as f32) * t).round() as u8))
+    })
+}
+
+/// Fade into color and then out of it
+fn dip(from: &RgbaImage, to: &RgbaImage, t: f32, color: Rgba<u8>) -> RgbaImage {
+    let solid = RgbaImage::from_pixel(from.width(), from.height(), color);
+    match t < 0.5 {
+        true => blend(from, &solid, t * 2.0),
+        false => blend(&solid, to, (t - 0.5) * 2.0)
+    }
+}